@@ -0,0 +1,306 @@
+use crate::index::Index;
+use anyhow::{ensure, Context, Result};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+
+// Inverted-file index with product quantization (IVF-PQ) over the index vectors.
+//
+// Each vector x is assigned to its nearest coarse centroid c, and the residual x - c is
+// compressed to `m` one-byte codes, one per `dsub`-dimensional subspace. At query time
+// q·x = q·c + q·(x - c), where the second term is approximated with per-subspace lookup
+// tables. Only the `nprobe` lists whose centroids score best against the query are
+// scanned, and the best candidates are re-scored exactly against the stored vectors.
+//
+// Section layout (little endian):
+//   nlist, m, dsub, ksub           u32 each
+//   centroids                      nlist x dim f32
+//   codebooks                      m x ksub x dsub f32
+//   list_offsets                   (nlist + 1) x u64 into ids/codes
+//   ids                            total_lines x u32, grouped by list
+//   codes                          total_lines x m u8, same order as ids
+
+const KMEANS_ITERS: usize = 8;
+/// Training sample rows per coarse centroid
+const SAMPLE_PER_LIST: usize = 32;
+const MAX_LISTS: usize = 1024;
+
+/// Parsed view of the ANN section of a mapped index
+pub struct Ann<'a> {
+    nlist: usize,
+    m: usize,
+    dsub: usize,
+    ksub: usize,
+    centroids: Vec<f32>,
+    codebooks: Vec<f32>,
+    list_offsets: Vec<usize>,
+    ids: &'a [u8],
+    codes: &'a [u8],
+}
+
+impl<'a> Ann<'a> {
+    pub fn parse(bytes: &'a [u8], dim: usize, total_lines: usize) -> Result<Self> {
+        let mut pos = 0usize;
+        let mut take = |n: usize| -> Result<&'a [u8]> {
+            let out = bytes.get(pos..pos + n).context("truncated ANN section")?;
+            pos += n;
+            Ok(out)
+        };
+        let mut header = [0usize; 4];
+        for field in header.iter_mut() {
+            *field = u32::from_le_bytes(take(4)?.try_into()?) as usize;
+        }
+        let [nlist, m, dsub, ksub] = header;
+        ensure!(
+            m * dsub == dim && nlist > 0,
+            "ANN section doesn't match index"
+        );
+
+        let centroids = read_f32s(take(nlist * dim * 4)?);
+        let codebooks = read_f32s(take(m * ksub * dsub * 4)?);
+        let list_offsets: Vec<usize> = take((nlist + 1) * 8)?
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()) as usize)
+            .collect();
+        ensure!(
+            list_offsets.last() == Some(&total_lines),
+            "ANN section doesn't match index"
+        );
+        let ids = take(total_lines * 4)?;
+        let codes = take(total_lines * m)?;
+        Ok(Self {
+            nlist,
+            m,
+            dsub,
+            ksub,
+            centroids,
+            codebooks,
+            list_offsets,
+            ids,
+            codes,
+        })
+    }
+
+    /// Approximate top-`top` lines for a normalized query, re-scored exactly.
+    /// Returns (line, score) sorted by descending score.
+    pub fn search(
+        &self,
+        index: &Index,
+        query: &[f32],
+        top: usize,
+        nprobe: usize,
+    ) -> Vec<(usize, f32)> {
+        let dim = self.m * self.dsub;
+
+        // Coarse lists to probe
+        let mut lists: Vec<(usize, f32)> = self
+            .centroids
+            .chunks_exact(dim)
+            .map(|c| dot(query, c))
+            .enumerate()
+            .collect();
+        lists.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        lists.truncate(nprobe.clamp(1, self.nlist));
+
+        // Per-subspace lookup tables of q_j · codeword
+        let mut lut = vec![0f32; self.m * self.ksub];
+        for j in 0..self.m {
+            let q = &query[j * self.dsub..(j + 1) * self.dsub];
+            for k in 0..self.ksub {
+                let at = (j * self.ksub + k) * self.dsub;
+                lut[j * self.ksub + k] = dot(q, &self.codebooks[at..at + self.dsub]);
+            }
+        }
+
+        let mut candidates: Vec<(usize, f32)> = lists
+            .par_iter()
+            .flat_map_iter(|&(list, base)| {
+                let range = self.list_offsets[list]..self.list_offsets[list + 1];
+                let lut = &lut;
+                range.map(move |pos| {
+                    let codes = &self.codes[pos * self.m..(pos + 1) * self.m];
+                    let approx: f32 = codes
+                        .iter()
+                        .enumerate()
+                        .map(|(j, &c)| lut[j * self.ksub + c as usize])
+                        .sum();
+                    let id = u32::from_le_bytes(self.ids[pos * 4..pos * 4 + 4].try_into().unwrap());
+                    (id as usize, base + approx)
+                })
+            })
+            .collect();
+
+        // Keep a generous shortlist and re-score it exactly
+        let shortlist = (top * 10).max(100);
+        if candidates.len() > shortlist {
+            candidates.select_nth_unstable_by(shortlist, |a, b| {
+                b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal)
+            });
+            candidates.truncate(shortlist);
+        }
+        let mut exact: Vec<(usize, f32)> = candidates
+            .par_iter()
+            .map(|&(id, _)| (id, index.dot(id, query)))
+            .collect();
+        exact.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        exact.truncate(top);
+        exact
+    }
+}
+
+/// Train IVF-PQ on the index vectors and serialize the section.
+/// The index must hold at least one line.
+pub fn build(index: &Index) -> Vec<u8> {
+    let dim = index.dim;
+    let n = index.len();
+    let nlist = ((n as f64).sqrt() as usize).clamp(1, MAX_LISTS);
+    // Sub-vectors of 8 dims (or the largest divisor of dim below that)
+    let dsub = (1..=8).rev().find(|&d| dim.is_multiple_of(d)).unwrap_or(1);
+    let m = dim / dsub;
+
+    // Deterministic strided training sample
+    let sample_n = n.min(nlist * SAMPLE_PER_LIST);
+    let sample: Vec<f32> = (0..sample_n)
+        .flat_map(|s| index.vector(s * n / sample_n))
+        .collect();
+    let centroids = kmeans(&sample, dim, nlist.min(sample_n), KMEANS_ITERS);
+    let nlist = centroids.len() / dim.max(1);
+
+    // Residuals of the sample train one codebook per subspace
+    let residuals: Vec<f32> = sample
+        .par_chunks_exact(dim)
+        .flat_map_iter(|x| {
+            let c = nearest(x, &centroids, dim);
+            x.iter()
+                .zip(&centroids[c * dim..(c + 1) * dim])
+                .map(|(a, b)| a - b)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let ksub = sample_n.clamp(1, 256);
+    let codebooks: Vec<Vec<f32>> = (0..m)
+        .into_par_iter()
+        .map(|j| {
+            let sub: Vec<f32> = residuals
+                .chunks_exact(dim)
+                .flat_map(|r| r[j * dsub..(j + 1) * dsub].iter().copied())
+                .collect();
+            let mut book = kmeans(&sub, dsub, ksub, KMEANS_ITERS);
+            // Pad so every subspace has exactly ksub codewords
+            book.resize(ksub * dsub, 0.0);
+            book
+        })
+        .collect();
+
+    // Assign and encode every vector
+    let assigned: Vec<(u32, Vec<u8>)> = (0..n)
+        .into_par_iter()
+        .map(|i| {
+            let x = index.vector(i);
+            let c = nearest(&x, &centroids, dim);
+            let codes = (0..m)
+                .map(|j| {
+                    let r: Vec<f32> = (j * dsub..(j + 1) * dsub)
+                        .map(|t| x[t] - centroids[c * dim + t])
+                        .collect();
+                    nearest(&r, &codebooks[j], dsub) as u8
+                })
+                .collect();
+            (c as u32, codes)
+        })
+        .collect();
+
+    // Group by list (counting sort keeps ids ascending within a list)
+    let mut list_offsets = vec![0usize; nlist + 1];
+    for (c, _) in &assigned {
+        list_offsets[*c as usize + 1] += 1;
+    }
+    for l in 0..nlist {
+        list_offsets[l + 1] += list_offsets[l];
+    }
+    let mut cursor = list_offsets.clone();
+    let mut ids = vec![0u32; n];
+    let mut codes = vec![0u8; n * m];
+    for (i, (c, code)) in assigned.into_iter().enumerate() {
+        let pos = cursor[c as usize];
+        cursor[c as usize] += 1;
+        ids[pos] = i as u32;
+        codes[pos * m..(pos + 1) * m].copy_from_slice(&code);
+    }
+
+    let mut out = Vec::new();
+    for field in [nlist, m, dsub, ksub] {
+        out.extend_from_slice(&(field as u32).to_le_bytes());
+    }
+    for x in centroids.iter().chain(codebooks.iter().flatten()) {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+    for off in list_offsets {
+        out.extend_from_slice(&(off as u64).to_le_bytes());
+    }
+    for id in ids {
+        out.extend_from_slice(&id.to_le_bytes());
+    }
+    out.extend_from_slice(&codes);
+    out
+}
+
+fn read_f32s(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn sq_dist(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Index of the closest centroid (squared L2)
+fn nearest(x: &[f32], centroids: &[f32], d: usize) -> usize {
+    centroids
+        .chunks_exact(d)
+        .map(|c| sq_dist(x, c))
+        .enumerate()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Lloyd's k-means over `data` rows of `d` dims, seeded with evenly spaced rows
+fn kmeans(data: &[f32], d: usize, k: usize, iters: usize) -> Vec<f32> {
+    let n = data.len() / d.max(1);
+    let k = k.min(n);
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut centroids: Vec<f32> = (0..k)
+        .flat_map(|c| data[(c * n / k) * d..(c * n / k + 1) * d].iter().copied())
+        .collect();
+
+    for _ in 0..iters {
+        let assign: Vec<usize> = data
+            .par_chunks_exact(d)
+            .map(|x| nearest(x, &centroids, d))
+            .collect();
+        let mut sums = vec![0f32; k * d];
+        let mut counts = vec![0usize; k];
+        for (x, &c) in data.chunks_exact(d).zip(&assign) {
+            counts[c] += 1;
+            for (s, v) in sums[c * d..(c + 1) * d].iter_mut().zip(x) {
+                *s += v;
+            }
+        }
+        for c in 0..k {
+            // Empty clusters keep their previous centroid
+            if counts[c] > 0 {
+                for t in 0..d {
+                    centroids[c * d + t] = sums[c * d + t] / counts[c] as f32;
+                }
+            }
+        }
+    }
+    centroids
+}
@@ -0,0 +1,270 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Lines per cache entry. Chunking each source keeps appends to a log file cheap:
+/// only the last (changed) chunk has to be re-embedded.
+const CHUNK_LINES: usize = 4096;
+
+const MAGIC: &[u8; 4] = b"VGC1";
+
+/// On-disk cache of normalized embeddings, keyed by model ID + content hash.
+/// Entries are evicted least-recently-used first once the cache exceeds `max_bytes`.
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    model_id: String,
+    max_bytes: u64,
+}
+
+/// Totals reported by `vecgrep cache stats`
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// `$XDG_CACHE_HOME/vecgrep` (or the platform equivalent)
+pub fn default_dir() -> Result<PathBuf> {
+    let base = dirs::cache_dir().context("could not determine cache directory")?;
+    Ok(base.join("vecgrep"))
+}
+
+/// Parse sizes like `512M`, `2G` or plain bytes
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, mult) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let mult = match c.to_ascii_uppercase() {
+                'K' => 1u64 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("unknown size suffix in '{}'", s)),
+            };
+            (&s[..i], mult)
+        }
+        _ => (s, 1),
+    };
+    let n = digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+    n.checked_mul(mult)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+impl EmbeddingCache {
+    pub fn open(dir: PathBuf, model_id: &str, max_bytes: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create cache dir {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            model_id: model_id.to_string(),
+            max_bytes,
+        })
+    }
+
+    /// Embed `lines`, reusing cached vectors per chunk of each source range.
+    /// All misses are encoded together in a single `encode` call to keep batching effective.
+    /// `encode` must return normalized vectors, which is what gets stored.
+    pub fn encode(
+        &self,
+        lines: &[String],
+        ranges: &[Range<usize>],
+        encode: impl FnOnce(&[String]) -> Vec<Vec<f32>>,
+    ) -> Vec<Vec<f32>> {
+        let mut out: Vec<Option<Vec<f32>>> = vec![None; lines.len()];
+        let mut missing: Vec<(Range<usize>, PathBuf)> = Vec::new();
+
+        for range in ranges {
+            let mut start = range.start;
+            while start < range.end {
+                let end = (start + CHUNK_LINES).min(range.end);
+                let path = self.entry_path(&lines[start..end]);
+                match read_entry(&path, end - start) {
+                    Some(vecs) => {
+                        touch(&path);
+                        for (slot, v) in out[start..end].iter_mut().zip(vecs) {
+                            *slot = Some(v);
+                        }
+                    }
+                    None => missing.push((start..end, path)),
+                }
+                start = end;
+            }
+        }
+
+        if !missing.is_empty() {
+            let texts: Vec<String> = missing
+                .iter()
+                .flat_map(|(r, _)| lines[r.clone()].iter().cloned())
+                .collect();
+            let mut fresh = encode(&texts).into_iter();
+            for (r, path) in &missing {
+                let vecs: Vec<Vec<f32>> = fresh.by_ref().take(r.len()).collect();
+                if let Err(err) = write_entry(path, &vecs) {
+                    eprintln!("vecgrep: failed to write cache entry: {:#}", err);
+                }
+                for (slot, v) in out[r.clone()].iter_mut().zip(vecs) {
+                    *slot = Some(v);
+                }
+            }
+            if let Err(err) = evict(&self.dir, self.max_bytes) {
+                eprintln!("vecgrep: failed to evict cache entries: {:#}", err);
+            }
+        }
+
+        out.into_iter().map(Option::unwrap_or_default).collect()
+    }
+
+    fn entry_path(&self, lines: &[String]) -> PathBuf {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(MAGIC);
+        hasher.update(self.model_id.as_bytes());
+        hasher.update(&[0]);
+        for line in lines {
+            hasher.update(&(line.len() as u64).to_le_bytes());
+            hasher.update(line.as_bytes());
+        }
+        let hex = hasher.finalize().to_hex();
+        // Two-level fan-out keeps directories small for large codebases
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+}
+
+/// Count entries and bytes currently stored in `dir`
+pub fn stats(dir: &Path) -> Result<CacheStats> {
+    let entries = list_entries(dir)?;
+    Ok(CacheStats {
+        entries: entries.len(),
+        bytes: entries.iter().map(|e| e.1).sum(),
+    })
+}
+
+/// Remove every cache entry under `dir`
+pub fn clear(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir).with_context(|| format!("failed to remove {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+fn read_entry(path: &Path, count: usize) -> Option<Vec<Vec<f32>>> {
+    let mut buf = Vec::new();
+    File::open(path).ok()?.read_to_end(&mut buf).ok()?;
+    if buf.len() < 12 || &buf[..4] != MAGIC {
+        return None;
+    }
+    let dim = u32::from_le_bytes(buf[4..8].try_into().ok()?) as usize;
+    let n = u32::from_le_bytes(buf[8..12].try_into().ok()?) as usize;
+    if n != count || buf.len() != 12 + n * dim * 4 {
+        return None;
+    }
+    let floats: Vec<f32> = buf[12..]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    if dim == 0 {
+        return Some(vec![Vec::new(); n]);
+    }
+    Some(floats.chunks_exact(dim).map(<[f32]>::to_vec).collect())
+}
+
+fn write_entry(path: &Path, vecs: &[Vec<f32>]) -> Result<()> {
+    let dim = vecs.first().map_or(0, Vec::len);
+    if vecs.iter().any(|v| v.len() != dim) {
+        bail!("inconsistent embedding dimensions");
+    }
+    let mut buf = Vec::with_capacity(12 + vecs.len() * dim * 4);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&(dim as u32).to_le_bytes());
+    buf.extend_from_slice(&(vecs.len() as u32).to_le_bytes());
+    for x in vecs.iter().flatten() {
+        buf.extend_from_slice(&x.to_le_bytes());
+    }
+
+    let parent = path.parent().context("cache entry has no parent dir")?;
+    fs::create_dir_all(parent)?;
+    // Write to a temp file and rename so concurrent runs never read a partial entry
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    File::create(&tmp)?.write_all(&buf)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Mark an entry as recently used for LRU eviction
+fn touch(path: &Path) {
+    if let Ok(f) = File::options().append(true).open(path) {
+        let _ = f.set_modified(SystemTime::now());
+    }
+}
+
+/// (path, size, mtime) for every entry under `dir`
+fn list_entries(dir: &Path) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut entries = Vec::new();
+    if !dir.exists() {
+        return Ok(entries);
+    }
+    for shard in fs::read_dir(dir)? {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(shard.path())? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                let mtime = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                entries.push((entry.path(), meta.len(), mtime));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn evict(dir: &Path, max_bytes: u64) -> Result<()> {
+    let mut entries = list_entries(dir)?;
+    let mut total: u64 = entries.iter().map(|e| e.1).sum();
+    if total <= max_bytes {
+        return Ok(());
+    }
+    // Oldest access first
+    entries.sort_by_key(|e| e.2);
+    for (path, size, _) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if fs::remove_file(&path).is_ok() {
+            total -= size;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size(" 2g "), Ok(2 << 30));
+        assert_eq!(parse_size("1 T"), Ok(1 << 40));
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1K").is_err());
+    }
+
+    #[test]
+    fn oversized_sizes_are_errors() {
+        assert_eq!(parse_size("16777215T"), Ok(16777215 << 40));
+        assert_eq!(
+            parse_size("16777216T"),
+            Err("size '16777216T' is too large".to_string())
+        );
+        assert!(parse_size("18446744073709551616").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::cli::ChunkArgs;
+use crate::lexical::words;
+use anyhow::{ensure, Result};
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// Splits long lines into overlapping windows of words, so a short relevant passage
+/// isn't averaged away by the rest of a long line when mean-pooled
+pub struct Chunker {
+    size: usize,
+    overlap: usize,
+}
+
+impl Chunker {
+    /// `None` unless --chunk-size is given. The overlap defaults to a quarter window.
+    pub fn new(args: &ChunkArgs) -> Result<Option<Self>> {
+        let Some(size) = args.chunk_size else {
+            return Ok(None);
+        };
+        ensure!(size > 0, "--chunk-size must be at least 1");
+        let overlap = args.chunk_overlap.unwrap_or(size / 4);
+        ensure!(
+            overlap < size,
+            "--chunk-overlap ({}) must be smaller than --chunk-size ({})",
+            overlap,
+            size
+        );
+        Ok(Some(Self { size, overlap }))
+    }
+
+    /// Byte spans of the windows of `text`; a text of at most `size` words is one window
+    pub fn windows(&self, text: &str) -> Vec<Range<usize>> {
+        let words = words(text);
+        if words.len() <= self.size {
+            return std::iter::once(0..text.len()).collect();
+        }
+        let mut out = Vec::new();
+        let mut first = 0;
+        loop {
+            let last = (first + self.size).min(words.len());
+            out.push(words[first].start..words[last - 1].end);
+            if last == words.len() {
+                return out;
+            }
+            first += self.size - self.overlap;
+        }
+    }
+
+    pub fn split(&self, lines: &[String]) -> Windows {
+        let per_line: Vec<Vec<Range<usize>>> = lines.par_iter().map(|l| self.windows(l)).collect();
+        let mut windows = Windows::default();
+        for (line, spans) in lines.iter().zip(per_line) {
+            let start = windows.texts.len();
+            for span in spans {
+                windows.texts.push(line[span.clone()].to_string());
+                windows.spans.push(span);
+            }
+            windows.of_line.push(start..windows.texts.len());
+        }
+        windows
+    }
+}
+
+/// The windows of a batch of lines, embedded and scored in their place
+#[derive(Default)]
+pub struct Windows {
+    pub texts: Vec<String>,
+    /// Byte span of each window within its line
+    spans: Vec<Range<usize>>,
+    /// Windows of each line, as a range into `texts`
+    of_line: Vec<Range<usize>>,
+}
+
+impl Windows {
+    /// Window ranges covering the given ranges of lines (e.g. one per source)
+    pub fn ranges(&self, lines: &[Range<usize>]) -> Vec<Range<usize>> {
+        let start = |i: usize| self.of_line.get(i).map_or(self.texts.len(), |w| w.start);
+        lines.iter().map(|r| start(r.start)..start(r.end)).collect()
+    }
+
+    /// Per-line results from the per-window ones of `Queries::score_all`: each line keeps
+    /// its best window's score, per-query scores and span (`None` for unsplit lines)
+    #[allow(clippy::type_complexity)]
+    pub fn reduce(
+        &self,
+        scores: &[f32],
+        query_scores: &[f32],
+        excluded: &[bool],
+    ) -> (Vec<f32>, Vec<f32>, Vec<bool>, Vec<Option<Range<usize>>>) {
+        let nq = query_scores.len() / self.texts.len().max(1);
+        let mut out = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for range in &self.of_line {
+            let dropped = |w: usize| excluded.get(w).copied().unwrap_or(false);
+            let w = pick(range.clone().map(|w| (scores[w], dropped(w))))
+                .map_or(range.start, |k| range.start + k);
+            out.0.push(scores[w]);
+            out.1.extend_from_slice(&query_scores[w * nq..(w + 1) * nq]);
+            if !excluded.is_empty() {
+                out.2.push(dropped(w));
+            }
+            out.3.push((range.len() > 1).then(|| self.spans[w].clone()));
+        }
+        out
+    }
+}
+
+/// Index of the best-scoring window not dropped by --not, `None` if all are dropped
+pub fn pick(windows: impl Iterator<Item = (f32, bool)>) -> Option<usize> {
+    windows
+        .enumerate()
+        .filter(|(_, (_, dropped))| !dropped)
+        .fold(None, |acc, (i, (score, _))| match acc {
+            Some((_, best)) if best >= score => acc,
+            _ => Some((i, score)),
+        })
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(size: usize, overlap: usize, text: &str) -> Vec<&str> {
+        let chunker = Chunker { size, overlap };
+        chunker
+            .windows(text)
+            .into_iter()
+            .map(|span| &text[span])
+            .collect()
+    }
+
+    #[test]
+    fn short_text_is_one_window() {
+        assert_eq!(
+            windows(4, 1, "  (just four words) "),
+            ["  (just four words) "]
+        );
+        assert_eq!(windows(4, 1, ""), [""]);
+    }
+
+    #[test]
+    fn windows_overlap_and_cover_the_end() {
+        assert_eq!(windows(4, 1, "a b c d e f g"), ["a b c d", "d e f g"]);
+        assert_eq!(
+            windows(4, 2, "a b c d e f g"),
+            ["a b c d", "c d e f", "e f g"]
+        );
+        assert_eq!(
+            windows(3, 0, "one two, three: four"),
+            ["one two, three", "four"]
+        );
+    }
+}
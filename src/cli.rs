@@ -0,0 +1,489 @@
+use crate::{cache, lexical, stats, stream};
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "vecgrep",
+    version,
+    about = "Semantic grep powered by model2vec-rs",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    group(ArgGroup::new("streaming").multiple(true).args(["stream", "follow"]))
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Query string to search for semantically similar lines
+    /// (with -e/-f this is the first path instead, like grep).
+    /// A query that is a subcommand name (cache, index, search, help) must follow `--` or use -e
+    #[arg(required_unless_present_any = ["queries", "query_file"])]
+    pub query: Option<String>,
+
+    #[command(flatten)]
+    pub queries: QueryArgs,
+
+    /// Files or directories to search recursively (reads stdin if omitted; '-' for stdin),
+    /// or files to follow with --follow
+    pub paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub matching: MatchArgs,
+
+    #[command(flatten)]
+    pub model_args: ModelArgs,
+
+    /// Stream mode: process and print incrementally for non-stopping input
+    #[arg(long = "stream", action = ArgAction::SetTrue, conflicts_with = "stats_only")]
+    pub stream: bool,
+
+    /// Follow the given files like `tail -F` (through truncation and rotation) and search
+    /// their new lines as they are written; implies --stream
+    #[arg(long = "follow", action = ArgAction::SetTrue, conflicts_with_all = ["stats_only", "table"])]
+    pub follow: bool,
+
+    /// With --stream, score buffered lines after this long even if --batch-size isn't reached
+    #[arg(long = "flush-interval", value_name = "DURATION", default_value = "200ms", value_parser = stream::parse_duration)]
+    pub flush_interval: Duration,
+
+    /// With --stream, lines (or records) read ahead of the scorer before --overflow applies
+    #[arg(long = "queue-size", value_name = "LINES", default_value_t = 8192)]
+    pub queue_size: usize,
+
+    /// With --stream, what to do when input outpaces scoring and the queue is full
+    #[arg(long = "overflow", value_enum, default_value_t = Overflow::Block)]
+    pub overflow: Overflow,
+
+    /// With --stream --top, print the top-N lines of every N lines read
+    #[arg(
+        long = "window-lines",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "streaming",
+        requires = "top",
+        conflicts_with_all = ["window_secs", "after", "before"]
+    )]
+    pub window_lines: Option<u64>,
+
+    /// With --stream --top, print the top-N lines of every S seconds
+    #[arg(
+        long = "window-secs",
+        value_name = "S",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "streaming",
+        requires = "top",
+        conflicts_with_all = ["after", "before"]
+    )]
+    pub window_secs: Option<u64>,
+
+    /// With --stream, match lines above this running percentile of the stream's scores
+    /// (e.g. p99.9) instead of a fixed -t
+    #[arg(
+        long = "auto-threshold",
+        value_name = "PERCENTILE",
+        value_parser = stats::parse_percentile,
+        requires = "streaming",
+        conflicts_with_all = ["threshold", "top"]
+    )]
+    pub auto_threshold: Option<f64>,
+
+    /// With --stream, report the score distribution so far on stderr this often (on Unix also on SIGUSR1)
+    #[arg(long = "stats-interval", value_name = "DURATION", value_parser = stream::parse_duration, requires = "streaming")]
+    pub stats_interval: Option<Duration>,
+
+    /// Don't read or write the on-disk embedding cache
+    #[arg(long = "no-cache", action = ArgAction::SetTrue)]
+    pub no_cache: bool,
+
+    #[command(flatten)]
+    pub cache: CacheArgs,
+
+    #[command(flatten)]
+    pub walk: WalkArgs,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+
+    #[command(flatten)]
+    pub records: RecordArgs,
+
+    #[command(flatten)]
+    pub chunk: ChunkArgs,
+
+    #[command(flatten)]
+    pub fields: FieldArgs,
+
+    #[command(flatten)]
+    pub preprocess: PreprocessArgs,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Stop reading until the scorer catches up (backpressure on the writer)
+    Block,
+    /// Discard the oldest queued lines, counting them
+    DropOldest,
+    /// Discard every other queued line, keeping an even sample
+    Sample,
+}
+
+/// Clean up the text that gets embedded; printed lines stay as they are
+#[derive(Args, Debug)]
+pub struct PreprocessArgs {
+    /// Normalize text before embedding (comma-separated), e.g. uuid,ip,number or all
+    #[arg(
+        long = "normalize",
+        value_name = "STEPS",
+        value_enum,
+        value_delimiter = ','
+    )]
+    pub normalize: Vec<Normalization>,
+
+    /// Remove a leading match of this regex before embedding, e.g. '^\S+ \S+ \[\w+\] '
+    #[arg(long = "strip-prefix", value_name = "REGEX")]
+    pub strip_prefix: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// Strip ANSI color and terminal escape codes
+    Ansi,
+    /// Mask UUIDs as <uuid>
+    Uuid,
+    /// Mask IPv4/IPv6 addresses (with ports) as <ip>
+    Ip,
+    /// Mask 0x... values and long hex strings with digits (hashes, addresses) as <hex>
+    Hex,
+    /// Mask numbers as <num>
+    Number,
+    /// Mask dates and times as <time>
+    Timestamp,
+    /// Lowercase everything
+    Lowercase,
+    /// All of the above
+    All,
+}
+
+/// Embed only some fields of structured log lines; the whole line is still printed
+#[derive(Args, Debug)]
+pub struct FieldArgs {
+    /// Embed only this field of JSON lines (repeatable; dotted paths like error.message)
+    #[arg(
+        long = "json-field",
+        visible_alias = "field",
+        value_name = "PATH",
+        conflicts_with = "chunk_size"
+    )]
+    pub json_fields: Vec<String>,
+
+    /// How lines are parsed for --json-field: JSON objects or logfmt key=value pairs
+    #[arg(long = "format", value_enum, default_value_t = LogFormat::Json, requires = "json_fields")]
+    pub format: LogFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,
+    Logfmt,
+}
+
+/// Score long lines or records by their best-matching window of words
+#[derive(Args, Debug)]
+pub struct ChunkArgs {
+    /// Split lines longer than WORDS words into overlapping windows; a line scores as its best window
+    #[arg(long = "chunk-size", value_name = "WORDS")]
+    pub chunk_size: Option<usize>,
+
+    /// Words shared by consecutive windows (default: a quarter of --chunk-size)
+    #[arg(long = "chunk-overlap", value_name = "WORDS", requires = "chunk_size")]
+    pub chunk_overlap: Option<usize>,
+}
+
+/// Embed, score and print multi-line records or code units instead of single lines
+#[derive(Args, Debug)]
+pub struct RecordArgs {
+    /// Start a new record at each line matching this regex, e.g. '^\d{4}-\d{2}-\d{2}'
+    #[arg(long = "record-start", value_name = "REGEX", conflicts_with_all = ["paragraph", "null_data"])]
+    pub record_start: Option<String>,
+
+    /// Treat blank-line separated blocks as records
+    #[arg(long = "paragraph", action = ArgAction::SetTrue, conflicts_with = "null_data")]
+    pub paragraph: bool,
+
+    /// Treat NUL-separated chunks as records (e.g. from `find -print0` or `git log -z`)
+    #[arg(short = 'z', long = "null-data", action = ArgAction::SetTrue, conflicts_with = "code")]
+    pub null_data: bool,
+
+    /// Search source code by function, method and class instead of by line (implies -n)
+    #[arg(long = "code", action = ArgAction::SetTrue, conflicts_with_all = ["record_start", "paragraph", "streaming"])]
+    pub code: bool,
+
+    /// Read CSV with a header row; matching rows are printed as CSV with a score column
+    #[arg(long = "csv", action = ArgAction::SetTrue, group = "table", conflicts_with_all = ["record_start", "paragraph", "null_data", "code", "json_fields", "chunk_size", "after", "before", "vimgrep", "explain"])]
+    pub csv: bool,
+
+    /// Like --csv for tab-separated input
+    #[arg(long = "tsv", action = ArgAction::SetTrue, group = "table", conflicts_with_all = ["record_start", "paragraph", "null_data", "code", "json_fields", "chunk_size", "after", "before", "vimgrep", "explain"])]
+    pub tsv: bool,
+
+    /// Embed only this CSV/TSV column, by header name or 1-based index (default: whole row)
+    #[arg(long = "csv-column", value_name = "NAME|INDEX", requires = "table")]
+    pub csv_column: Option<String>,
+}
+
+impl RecordArgs {
+    /// Field delimiter with --csv/--tsv
+    pub fn delimiter(&self) -> Option<char> {
+        if self.csv {
+            Some(',')
+        } else if self.tsv {
+            Some('\t')
+        } else {
+            None
+        }
+    }
+}
+
+/// Cheap checks that keep lines from being embedded at all
+#[derive(Args, Debug)]
+pub struct FilterArgs {
+    /// Only embed and score lines matching this regex (others can still show as context)
+    #[arg(long = "prefilter", visible_alias = "require", value_name = "REGEX")]
+    pub prefilter: Option<String>,
+
+    /// Never embed or score lines matching this regex, e.g. 'DEBUG|GET /health'
+    #[arg(long = "exclude", value_name = "REGEX")]
+    pub exclude: Option<String>,
+}
+
+/// Several queries in one pass; a line matches if any query matches it
+#[derive(Args, Debug)]
+pub struct QueryArgs {
+    /// Query to search for (repeatable); matches are tagged with the queries they hit
+    #[arg(short = 'e', long = "query")]
+    pub queries: Vec<String>,
+
+    /// Read queries from a file, one per line ('#' comments and blank lines are skipped)
+    #[arg(short = 'f', long = "query-file")]
+    pub query_file: Option<PathBuf>,
+
+    /// Negative query (repeatable): drop lines at least --not-threshold similar to it
+    #[arg(long = "not", value_name = "QUERY")]
+    pub not: Vec<String>,
+
+    /// Similarity to a --not query at which a line is dropped
+    #[arg(long = "not-threshold", default_value_t = 0.6)]
+    pub not_threshold: f32,
+
+    /// Instead of dropping, score lines as sim(query) - WEIGHT * sim(closest --not query)
+    #[arg(long = "not-weight", value_name = "WEIGHT")]
+    pub not_weight: Option<f32>,
+
+    /// Hybrid scoring: blend in BM25 keyword relevance, (1 - WEIGHT) * cosine + WEIGHT * BM25
+    #[arg(
+        long = "lexical-weight",
+        value_name = "WEIGHT",
+        default_value_t = 0.0,
+        value_parser = lexical::parse_weight
+    )]
+    pub lexical_weight: f32,
+}
+
+/// How matches are selected and printed
+#[derive(Args, Debug)]
+pub struct MatchArgs {
+    /// Similarity threshold in [0,1]. Matches below are filtered out
+    #[arg(short = 't', long = "threshold", default_value_t = 0.6)]
+    pub threshold: f32,
+
+    /// Number of context lines to show after each match (like grep -A)
+    #[arg(short = 'A', default_value_t = 0)]
+    pub after: usize,
+
+    /// Number of context lines to show before each match (like grep -B)
+    #[arg(short = 'B', default_value_t = 0)]
+    pub before: usize,
+
+    /// Hide similarity score for each matching line
+    #[arg(long = "hide-scores", action = ArgAction::SetTrue)]
+    pub hide_scores: bool,
+
+    /// Return top-N most similar lines (disables threshold; with --stream, per --window-lines/--window-secs)
+    #[arg(long = "top")]
+    pub top: Option<usize>,
+
+    /// Show the words that contributed most to each match (highlighted, listed after the score)
+    #[arg(long = "explain", action = ArgAction::SetTrue)]
+    pub explain: bool,
+
+    /// Print JSON Lines: one object per match/context line, then a summary object
+    #[arg(long = "json", action = ArgAction::SetTrue)]
+    pub json: bool,
+
+    /// Prefix each line with its 1-based line number (always on when searching files)
+    #[arg(short = 'n', long = "line-number", action = ArgAction::SetTrue)]
+    pub line_number: bool,
+
+    /// Prefix each line with the 0-based byte offset of its start within its input
+    #[arg(short = 'b', long = "byte-offset", action = ArgAction::SetTrue)]
+    pub byte_offset: bool,
+
+    /// Prefix match lines with the 1-based column of their first non-blank character (implies -n)
+    #[arg(long = "column", action = ArgAction::SetTrue)]
+    pub column: bool,
+
+    /// Print each match as path:line:column:text for editor quickfix lists (no context or separators)
+    #[arg(long = "vimgrep", action = ArgAction::SetTrue, conflicts_with_all = ["after", "before", "json"])]
+    pub vimgrep: bool,
+
+    /// When to color output; `auto` colors a terminal unless NO_COLOR is set (colors: VECGREP_COLORS)
+    #[arg(long = "color", value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Format of the end-of-run statistics (match count, mean, stddev, percentiles, histogram)
+    #[arg(long = "stats-format", value_enum, default_value_t = StatsFormat::Text)]
+    pub stats_format: StatsFormat,
+
+    /// Print only the statistics (to stdout), not the matching lines
+    #[arg(long = "stats-only", action = ArgAction::SetTrue)]
+    pub stats_only: bool,
+
+    /// Delimiter of --csv/--tsv input, whose matching rows print as rows plus a score column
+    #[arg(skip)]
+    pub table: Option<char>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Args, Debug)]
+pub struct ModelArgs {
+    /// Model ID from Hugging Face or local path (env: VECGREP_MODEL)
+    #[arg(
+        short = 'm',
+        long = "model",
+        env = "VECGREP_MODEL",
+        default_value = "minishlab/potion-base-8M"
+    )]
+    pub model: String,
+
+    /// Batch size for encoding (tune perf / memory)
+    #[arg(long = "batch-size", default_value_t = 1024)]
+    pub batch_size: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct CacheArgs {
+    /// Maximum embedding cache size, e.g. 512M or 2G; least recently used entries are evicted
+    #[arg(
+        long = "cache-size",
+        env = "VECGREP_CACHE_SIZE",
+        default_value = "1G",
+        value_parser = cache::parse_size
+    )]
+    pub cache_size: u64,
+}
+
+/// Filters applied while walking directories, mirroring ripgrep's defaults
+#[derive(Args, Debug)]
+pub struct WalkArgs {
+    /// Search hidden files and directories
+    #[arg(long = "hidden", action = ArgAction::SetTrue)]
+    pub hidden: bool,
+
+    /// Don't respect .gitignore, .ignore and global git excludes
+    #[arg(long = "no-ignore", action = ArgAction::SetTrue)]
+    pub no_ignore: bool,
+
+    /// Include or exclude (with '!') files matching a glob when walking directories (repeatable)
+    #[arg(short = 'g', long = "glob")]
+    pub globs: Vec<String>,
+
+    /// Only search files of this type, e.g. rust, py, js (repeatable)
+    #[arg(long = "type")]
+    pub types: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect or clear the on-disk embedding cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+
+        #[command(flatten)]
+        cache: CacheArgs,
+    },
+    /// Embed files once into a persistent index; re-running only re-embeds changed files
+    Index(IndexArgs),
+    /// Query a persistent index built with `vecgrep index`
+    Search(SearchArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheAction {
+    /// Show cache location, number of entries and total size
+    Stats,
+    /// Delete all cached embeddings
+    Clear,
+}
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    /// Files or directories to index
+    #[arg(default_value = ".")]
+    pub paths: Vec<PathBuf>,
+
+    /// Index file to create or update
+    #[arg(short = 'o', long = "index", default_value = ".vecgrep.idx")]
+    pub index: PathBuf,
+
+    /// Also build an approximate nearest-neighbour structure (IVF-PQ) for fast --top searches;
+    /// kept up to date on later runs once built
+    #[arg(long = "ann", action = ArgAction::SetTrue)]
+    pub ann: bool,
+
+    #[command(flatten)]
+    pub model_args: ModelArgs,
+
+    #[command(flatten)]
+    pub walk: WalkArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SearchArgs {
+    /// Query string to search for semantically similar lines
+    #[arg(required_unless_present_any = ["queries", "query_file"])]
+    pub query: Option<String>,
+
+    #[command(flatten)]
+    pub queries: QueryArgs,
+
+    /// Index file built by `vecgrep index`
+    #[arg(short = 'i', long = "index", default_value = ".vecgrep.idx")]
+    pub index: PathBuf,
+
+    /// Approximate search: number of clusters to scan for --top (higher = better recall, slower)
+    #[arg(long = "nprobe", default_value_t = 16)]
+    pub nprobe: usize,
+
+    /// Score every line exactly even if the index has an ANN structure
+    #[arg(long = "exact", action = ArgAction::SetTrue)]
+    pub exact: bool,
+
+    #[command(flatten)]
+    pub matching: MatchArgs,
+}
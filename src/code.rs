@@ -0,0 +1,404 @@
+use crate::record::Unit;
+use regex::Regex;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// How a language delimits the body of a function or class
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    /// `{ ... }` (Rust, C, C++, Java, Go, JS/TS, C#, Kotlin, Swift, PHP, ...)
+    Braces,
+    /// Indentation, or `end` at the declaration's indentation (Python, Ruby, Elixir)
+    Indent,
+}
+
+impl Lang {
+    /// Guess from the file extension; anything unknown (and stdin) is treated as braces
+    pub fn detect(path: Option<&Path>) -> Self {
+        let ext = path
+            .and_then(|p| p.extension())
+            .and_then(|e| e.to_str())
+            .unwrap_or("");
+        match ext {
+            "py" | "pyi" | "pyw" | "rb" | "ex" | "exs" | "nim" | "coffee" => Lang::Indent,
+            _ => Lang::Braces,
+        }
+    }
+}
+
+/// Keyword declarations, after any visibility and modifiers
+fn keyword_declaration() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r#"^(?:(?:export|default|pub(?:\([^)]*\))?|public|private|protected|internal|static|final|abstract|async|unsafe|const|extern(?:\s+"[^"]*")?|override|virtual|inline|open|suspend|sealed|data|partial)\s+)*(?P<keyword>fn|func|fun|function\*?|def|defp|defmodule|class|struct|enum|trait|impl|interface|mod|module|object|union|type\s+\w+\s+(?:struct|interface))(?:\s|[<(]|$)"#,
+        )
+        .expect("valid regex")
+    })
+}
+
+/// C-style definitions (`static int parse(const char *s) {`) and JS function bindings
+fn typed_declaration() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(
+            r"^(?:(?:[\w:<>,*&\[\]]+\s+)+[*&]*[\w:~]+\s*\([^;]*$|(?:export\s+)?(?:const|let|var)\s+\w+\s*=\s*(?:async\s*)?(?:function\b|\([^)]*\)\s*=>|\w+\s*=>))",
+        )
+        .expect("valid regex")
+    })
+}
+
+/// Statements that look like C-style definitions but aren't
+const CONTROL: &[&str] = &[
+    "if", "else", "for", "while", "switch", "match", "return", "catch", "do", "try", "new",
+    "throw", "case", "await", "yield", "let", "var", "using", "goto",
+];
+
+/// Keywords declaring a function, whose body holds statements rather than members
+const FUNCTIONS: &[&str] = &["fn", "func", "fun", "function", "def", "defp"];
+
+/// C-style definitions are only looked for where members can be declared (`members`),
+/// never among the statements of a function body
+fn is_declaration(line: &str, lang: Lang, members: bool) -> bool {
+    if keyword_declaration().is_match(line) {
+        return true;
+    }
+    let first = line
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next();
+    lang == Lang::Braces
+        && members
+        && !first.is_some_and(|w| CONTROL.contains(&w))
+        && typed_head(line)
+        && typed_declaration().is_match(line)
+}
+
+/// Whether a line can start a C-style definition: continuation lines (`&& f(x)`),
+/// struct literal fields (`name: value(x),`) and call arguments (`a, f(b),`) can't
+fn typed_head(line: &str) -> bool {
+    if line.starts_with(|c: char| "&|+-*/%=<>!?.^~".contains(c)) {
+        return false;
+    }
+    let head = &line[..line.find('(').unwrap_or(line.len())];
+    let mut angle = 0;
+    let mut chars = head.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '<' => angle += 1,
+            '>' => angle -= 1,
+            // `::` paths are fine, a lone `:` isn't
+            ':' if chars.next() != Some(':') => return false,
+            ',' if angle == 0 => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Classes, structs, impls and the like, whose body declares members
+fn holds_members(line: &str) -> bool {
+    keyword_declaration()
+        .captures(line)
+        .and_then(|caps| caps.name("keyword"))
+        .is_some_and(|kw| !FUNCTIONS.contains(&kw.as_str().trim_end_matches('*')))
+}
+
+/// Doc comments, attributes, annotations and decorators belong to the declaration below
+fn is_preamble(line: &str, lang: Lang) -> bool {
+    let prefixes: &[&str] = match lang {
+        Lang::Braces => &["//", "/*", "*", "#[", "@"],
+        Lang::Indent => &["#", "@"],
+    };
+    prefixes.iter().any(|p| line.starts_with(p))
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Net `{`/`}` count of a line and whether it opens a brace, skipping string and char
+/// literals and comments (`in_comment` carries a `/* */` comment across lines)
+fn braces(line: &str, in_comment: &mut bool) -> (i64, bool) {
+    let chars: Vec<char> = line.chars().collect();
+    let (mut net, mut opens) = (0, false);
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let (c, next) = (chars[i], chars.get(i + 1).copied());
+        if *in_comment {
+            if c == '*' && next == Some('/') {
+                i += 1;
+                *in_comment = false;
+            }
+        } else if let Some(q) = quote {
+            match c {
+                '\\' => i += 1,
+                c if c == q => quote = None,
+                _ => {}
+            }
+        } else {
+            match (c, next) {
+                ('/', Some('/')) => break,
+                ('/', Some('*')) => {
+                    i += 1;
+                    *in_comment = true;
+                }
+                ('"', _) => quote = Some('"'),
+                // A char literal or single-quoted string; not a Rust lifetime (`<'a`,
+                // `&'a`) or an apostrophe nothing on the line closes
+                ('\'', _)
+                    if !(i > 0 && matches!(chars[i - 1], '<' | '&'))
+                        && closes(&chars[i + 1..], '\'') =>
+                {
+                    quote = Some('\'')
+                }
+                ('{', _) => {
+                    net += 1;
+                    opens = true;
+                }
+                ('}', _) => net -= 1,
+                _ => {}
+            }
+        }
+        i += 1;
+    }
+    (net, opens)
+}
+
+/// Whether `quote` closes a literal in `rest`, skipping backslash escapes
+fn closes(rest: &[char], quote: char) -> bool {
+    let mut chars = rest.iter();
+    while let Some(&c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            c if c == quote => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// A declaration whose body hasn't ended yet
+struct Open {
+    indent: usize,
+    depth: i64,
+    opened: bool,
+    /// Its body declares members (a class) rather than holding statements (a function)
+    members: bool,
+}
+
+/// Split source into units: each function, method, class, etc. together with the comments
+/// and attributes directly above it, up to the end of its body or the next declaration
+/// (so a class is cut into its header and its methods). Lines between units, such as
+/// imports, form units of their own; those without any word (a lone `}`) are dropped.
+pub fn split(lines: &[String], offsets: &[u64], lang: Lang) -> Vec<Unit> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut start = 0;
+    let mut open: Option<Open> = None;
+    let mut depth = 0i64;
+    let mut in_comment = false;
+
+    for (i, line) in lines.iter().enumerate() {
+        let text = line.trim();
+        // Between declarations, or directly inside a class body
+        let members = match &open {
+            None => true,
+            Some(o) => o.members && o.opened && depth == o.depth + 1,
+        };
+        if is_declaration(text, lang, members) {
+            let mut head = i;
+            while head > start && is_preamble(lines[head - 1].trim(), lang) {
+                head -= 1;
+            }
+            ranges.push(start..head);
+            start = head;
+            open = Some(Open {
+                indent: indent(line),
+                depth,
+                opened: false,
+                members: holds_members(text),
+            });
+        } else if let (Lang::Indent, Some(o)) = (lang, &open) {
+            if !text.is_empty() && indent(line) <= o.indent {
+                // `end` closing the declaration is still part of it
+                let closes = indent(line) == o.indent
+                    && text.split(|c: char| !c.is_alphanumeric()).next() == Some("end");
+                let end = if closes { i + 1 } else { i };
+                ranges.push(start..end);
+                start = end;
+                open = None;
+            }
+        }
+
+        if lang == Lang::Braces {
+            let (net, opens) = braces(line, &mut in_comment);
+            depth += net;
+            if let Some(o) = &mut open {
+                o.opened |= opens;
+                // The body closed, or a declaration without one (`fn f();`) ended
+                if (o.opened && depth <= o.depth) || (!o.opened && text.ends_with(';')) {
+                    ranges.push(start..i + 1);
+                    start = i + 1;
+                    open = None;
+                }
+            }
+        }
+    }
+    ranges.push(start..lines.len());
+
+    ranges
+        .into_iter()
+        .filter_map(|mut range| {
+            while range.end > range.start && lines[range.end - 1].trim().is_empty() {
+                range.end -= 1;
+            }
+            while range.start < range.end && lines[range.start].trim().is_empty() {
+                range.start += 1;
+            }
+            let lines = &lines[range.clone()];
+            if !lines.iter().any(|l| l.chars().any(char::is_alphanumeric)) {
+                return None;
+            }
+            Some(Unit {
+                text: lines.join("\n"),
+                offset: offsets[range.start],
+                line_number: range.start + 1,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::split_lines;
+
+    /// First line number of each unit
+    fn starts(source: &str, lang: Lang) -> Vec<usize> {
+        let (lines, offsets) = split_lines(source.as_bytes());
+        split(&lines, &offsets, lang)
+            .iter()
+            .map(|unit| unit.line_number)
+            .collect()
+    }
+
+    #[test]
+    fn rust_bodies_stay_whole() {
+        let source = r#"use std::fmt;
+
+/// Build one
+fn build(line: &str) -> Open {
+    let open = Open {
+        indent: indent(line),
+        depth: depth(line),
+    };
+    check(open.depth, line)
+        && typed_declaration().is_match(line)
+}
+
+impl Open {
+    fn new() -> Self {
+        call(
+            a, bar(b),
+        )
+    }
+}
+"#;
+        assert_eq!(starts(source, Lang::Braces), [1, 3, 13, 14]);
+    }
+
+    #[test]
+    fn c_functions_and_class_members() {
+        let source = r#"#include <stdio.h>
+
+static int parse(const char *s)
+{
+    unsigned long total = sum(a,
+        b, scale(c),
+        d);
+    if (total > 0
+        && valid(s)) {
+        return 1;
+    }
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    return parse(argv[1]);
+}
+"#;
+        assert_eq!(starts(source, Lang::Braces), [1, 3, 15]);
+
+        let source = r#"class Parser {
+public:
+    int size() const {
+        return n;
+    }
+    std::map<int, int> counts(int from) {
+        return count(from,
+            std::map<int, int>());
+    }
+};
+"#;
+        assert_eq!(starts(source, Lang::Braces), [1, 3, 6]);
+    }
+
+    #[test]
+    fn quoted_braces_and_lifetimes() {
+        let source = r#"int a(char c) {
+    return c == '{' || c == '\'';
+}
+
+int b(void) {
+    return 0;
+}
+"#;
+        assert_eq!(starts(source, Lang::Braces), [1, 5]);
+
+        let source = r#"function open(s) {
+    return s.replace('{', '\'}');
+}
+
+int close(void) {
+    return 0;
+}
+"#;
+        assert_eq!(starts(source, Lang::Braces), [1, 5]);
+
+        let source = r#"impl<'a> Parser<'a> {
+    fn get(&self, x: &'a str) -> Option<&'a str> { g::<'a>(x, '}') }
+}
+
+int after(void) {
+    return 0;
+}
+"#;
+        assert_eq!(starts(source, Lang::Braces), [1, 2, 5]);
+    }
+
+    #[test]
+    fn python_by_indentation() {
+        let source = r#"import os
+
+class Store:
+    """Keeps things"""
+
+    def get(self, key,
+            default=None):
+        return self.items.get(key,
+                              default)
+
+    @cached
+    def keys(self):
+        return list(self.items)
+
+
+def main():
+    Store().get("a")
+"#;
+        assert_eq!(starts(source, Lang::Indent), [1, 3, 6, 11, 16]);
+    }
+}
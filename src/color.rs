@@ -0,0 +1,117 @@
+use crate::cli::ColorChoice;
+use std::env;
+use std::io::{self, IsTerminal};
+
+/// Scores at or above these bounds get the `high` / `mid` score color, the rest `low`
+const SCORE_HIGH: f32 = 0.8;
+const SCORE_MID: f32 = 0.65;
+
+/// SGR parameters (e.g. "1;31") for each part of the text output; empty means uncolored
+pub struct Palette {
+    pub path: String,
+    pub line: String,
+    pub matched: String,
+    pub context: String,
+    pub separator: String,
+    pub explain: String,
+    pub score_high: String,
+    pub score_mid: String,
+    pub score_low: String,
+}
+
+impl Palette {
+    /// No escapes at all, so the output is byte-for-byte the uncolored one
+    pub fn plain() -> Self {
+        Self {
+            path: String::new(),
+            line: String::new(),
+            matched: String::new(),
+            context: String::new(),
+            separator: String::new(),
+            explain: String::new(),
+            score_high: String::new(),
+            score_mid: String::new(),
+            score_low: String::new(),
+        }
+    }
+
+    fn colored() -> Self {
+        Self {
+            path: "35".into(),
+            line: "32".into(),
+            matched: "1".into(),
+            context: "2".into(),
+            separator: "36".into(),
+            explain: "1;4;31".into(),
+            score_high: "1;32".into(),
+            score_mid: "33".into(),
+            score_low: "2;33".into(),
+        }
+    }
+
+    /// Palette for this run: colored when `--color` (or, for `auto`, a terminal stdout
+    /// without NO_COLOR) asks for it, with overrides from VECGREP_COLORS
+    pub fn resolve(choice: ColorChoice) -> Self {
+        let enabled = match choice {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        };
+        if !enabled {
+            return Self::plain();
+        }
+        let mut palette = Self::colored();
+        if let Ok(spec) = env::var("VECGREP_COLORS") {
+            palette.apply(&spec);
+        }
+        palette
+    }
+
+    /// Apply `key=sgr` entries separated by ':', e.g. `match=1;31:context=:score_high=32`
+    fn apply(&mut self, spec: &str) {
+        for entry in spec.split(':').filter(|e| !e.is_empty()) {
+            let slot = entry.split_once('=').and_then(|(key, sgr)| {
+                let valid = sgr.bytes().all(|b| b.is_ascii_digit() || b == b';');
+                let slot = match key {
+                    "path" => &mut self.path,
+                    "line" => &mut self.line,
+                    "match" => &mut self.matched,
+                    "context" => &mut self.context,
+                    "sep" => &mut self.separator,
+                    "explain" => &mut self.explain,
+                    "score_high" => &mut self.score_high,
+                    "score_mid" => &mut self.score_mid,
+                    "score_low" => &mut self.score_low,
+                    _ => return None,
+                };
+                valid.then_some((slot, sgr))
+            });
+            match slot {
+                Some((slot, sgr)) => *slot = sgr.to_string(),
+                None => eprintln!("vecgrep: ignoring invalid VECGREP_COLORS entry '{}'", entry),
+            }
+        }
+    }
+
+    /// Color for a `[score]` by its strength
+    pub fn score(&self, score: f32) -> &str {
+        if score >= SCORE_HIGH {
+            &self.score_high
+        } else if score >= SCORE_MID {
+            &self.score_mid
+        } else {
+            &self.score_low
+        }
+    }
+}
+
+/// Wrap `text` in an SGR sequence (unchanged when `sgr` or `text` is empty)
+pub fn paint(sgr: &str, text: &str) -> String {
+    if sgr.is_empty() || text.is_empty() {
+        text.to_string()
+    } else {
+        format!("\x1b[{}m{}\x1b[0m", sgr, text)
+    }
+}
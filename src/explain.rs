@@ -0,0 +1,74 @@
+use crate::lexical::words;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Words reported per matching line
+const TOP_WORDS: usize = 3;
+
+/// A word of a matching line and how well it alone matches the query
+pub struct Contribution {
+    pub word: String,
+    pub score: f32,
+    /// Byte ranges of every occurrence of the word in the line
+    pub spans: Vec<Range<usize>>,
+}
+
+/// Top contributing words of each line, best first.
+///
+/// model2vec embeddings are mean-pooled token vectors, so the cosine between a word's own
+/// embedding and the query approximates how much it pulled the line towards the query.
+/// Words are embedded once across all lines with `encode` (normalized output) and
+/// scored against the query each line is explained by.
+pub fn explain(
+    lines: &[&str],
+    queries: &[&[f32]],
+    encode: impl FnOnce(&[String]) -> Vec<Vec<f32>>,
+) -> Vec<Vec<Contribution>> {
+    let spans: Vec<Vec<Range<usize>>> = lines.iter().map(|line| words(line)).collect();
+
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+    let mut unique: Vec<String> = Vec::new();
+    for (line, spans) in lines.iter().zip(&spans) {
+        for span in spans {
+            let word = &line[span.clone()];
+            ids.entry(word).or_insert_with(|| {
+                unique.push(word.to_string());
+                unique.len() - 1
+            });
+        }
+    }
+    if unique.is_empty() {
+        return lines.iter().map(|_| Vec::new()).collect();
+    }
+    let vectors = encode(&unique);
+
+    lines
+        .iter()
+        .zip(spans)
+        .zip(queries)
+        .map(|((line, spans), query)| {
+            let mut by_word: HashMap<&str, Contribution> = HashMap::new();
+            for span in spans {
+                let word = &line[span.clone()];
+                by_word
+                    .entry(word)
+                    .or_insert_with(|| Contribution {
+                        word: word.to_string(),
+                        score: dot(&vectors[ids[word]], query),
+                        spans: Vec::new(),
+                    })
+                    .spans
+                    .push(span);
+            }
+            let mut contributions: Vec<Contribution> =
+                by_word.into_values().filter(|c| c.score > 0.0).collect();
+            contributions.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.word.cmp(&b.word)));
+            contributions.truncate(TOP_WORDS);
+            contributions
+        })
+        .collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
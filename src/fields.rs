@@ -0,0 +1,187 @@
+use crate::cli::{FieldArgs, LogFormat};
+use rayon::prelude::*;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Picks the fields of structured log lines to embed, so timestamps, request IDs and
+/// hostnames don't drown out the message. The whole line is still what gets printed.
+pub struct Extractor {
+    format: LogFormat,
+    /// Field names as given (dotted paths for JSON)
+    fields: Vec<String>,
+    /// Lines embedded whole because they didn't parse or had none of the fields
+    fallbacks: AtomicUsize,
+}
+
+impl Extractor {
+    /// `None` unless --json-field is given
+    pub fn new(args: &FieldArgs) -> Option<Self> {
+        (!args.json_fields.is_empty()).then(|| Self {
+            format: args.format,
+            fields: args.json_fields.clone(),
+            fallbacks: AtomicUsize::new(0),
+        })
+    }
+
+    /// Text to embed for `line`: the values of the chosen fields joined by spaces,
+    /// or the whole line if it doesn't parse or has none of them
+    pub fn embed_text<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let values = match self.format {
+            LogFormat::Json => self.json_values(line),
+            LogFormat::Logfmt => self.logfmt_values(line),
+        };
+        match values {
+            Some(values) if !values.is_empty() => Cow::Owned(values.join(" ")),
+            _ => {
+                self.fallbacks.fetch_add(1, Ordering::Relaxed);
+                Cow::Borrowed(line)
+            }
+        }
+    }
+
+    pub fn embed_texts(&self, lines: &[String]) -> Vec<String> {
+        lines
+            .par_iter()
+            .map(|line| self.embed_text(line).into_owned())
+            .collect()
+    }
+
+    /// Warn about lines that were embedded whole, out of `total`
+    pub fn report(&self, total: usize) {
+        let fallbacks = self.fallbacks.load(Ordering::Relaxed);
+        if fallbacks > 0 {
+            let format = match self.format {
+                LogFormat::Json => "JSON",
+                LogFormat::Logfmt => "logfmt",
+            };
+            eprintln!(
+                "vecgrep: {} of {} lines weren't valid {} or had none of the --json-field fields; embedded them whole",
+                fallbacks, total, format
+            );
+        }
+    }
+
+    fn json_values(&self, line: &str) -> Option<Vec<String>> {
+        let root: Value = serde_json::from_str(line).ok()?;
+        Some(
+            self.fields
+                .iter()
+                .filter_map(|field| lookup(&root, field))
+                .filter_map(json_text)
+                .collect(),
+        )
+    }
+
+    fn logfmt_values(&self, line: &str) -> Option<Vec<String>> {
+        let pairs = logfmt(line);
+        if pairs.is_empty() {
+            return None;
+        }
+        Some(
+            self.fields
+                .iter()
+                .filter_map(|field| pairs.iter().find(|(key, _)| key == field))
+                .map(|(_, value)| value.clone())
+                .filter(|value| !value.is_empty())
+                .collect(),
+        )
+    }
+}
+
+/// Value at a dotted path (`error.message`, `items.0.name`); a key containing dots
+/// is matched as a whole first
+fn lookup<'a>(root: &'a Value, path: &str) -> Option<&'a Value> {
+    if let Some(value) = root.get(path) {
+        return Some(value);
+    }
+    path.split('.').try_fold(root, |value, key| match value {
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        _ => value.get(key),
+    })
+}
+
+/// Strings as-is, other values as JSON text; nulls are left out
+fn json_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// `key=value` pairs of a logfmt line; values may be double-quoted with `\"` escapes.
+/// Bare words without `=` are skipped.
+fn logfmt(line: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != '=') {
+            key.push(c);
+        }
+        if key.is_empty() && chars.peek().is_none() {
+            return pairs;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            // A bare word, or a stray '='
+            chars.next();
+            continue;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+        if !key.is_empty() {
+            pairs.push((key, value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn logfmt_pairs() {
+        assert_eq!(
+            logfmt(r#"level=error msg="db \"down\" now" dur=5ms"#),
+            pairs(&[
+                ("level", "error"),
+                ("msg", r#"db "down" now"#),
+                ("dur", "5ms")
+            ])
+        );
+        assert_eq!(
+            logfmt(r#"msg="unterminated"#),
+            pairs(&[("msg", "unterminated")])
+        );
+    }
+
+    #[test]
+    fn logfmt_skips_bare_words_and_stray_equals() {
+        assert!(logfmt("plain words here").is_empty());
+        assert_eq!(
+            logfmt("ts=1 bare key= =orphan x=2"),
+            pairs(&[("ts", "1"), ("key", ""), ("x", "2")])
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use crate::cli::FilterArgs;
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Regex checks deciding which lines are embedded and scored at all.
+/// Lines that fail them are never embedded but can still be printed as context.
+pub struct LineFilter {
+    prefilter: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl LineFilter {
+    pub fn new(args: &FilterArgs) -> Result<Self> {
+        Ok(Self {
+            prefilter: compile(args.prefilter.as_deref(), "--prefilter")?,
+            exclude: compile(args.exclude.as_deref(), "--exclude")?,
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.prefilter.is_some() || self.exclude.is_some()
+    }
+
+    pub fn keep(&self, line: &str) -> bool {
+        self.prefilter.as_ref().is_none_or(|re| re.is_match(line))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(line))
+    }
+}
+
+fn compile(pattern: Option<&str>, flag: &str) -> Result<Option<Regex>> {
+    pattern
+        .map(|re| Regex::new(re).with_context(|| format!("invalid {} regex '{}'", flag, re)))
+        .transpose()
+}
@@ -0,0 +1,181 @@
+use crate::record::{Framing, RecordReader, Unit};
+use anyhow::{Context, Result};
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How often a followed file is checked for new data, truncation and rotation
+const POLL: Duration = Duration::from_millis(250);
+
+/// Follow `path` like `tail -F`, handing every new record to `send`; only returns on a
+/// read error. A file present at the start is followed from its end; one that appears
+/// later, replaces it (rotation) or is truncated is read from its start. `send` is told which records are the first since the file was
+/// (re)opened, as what came before them is no context for them.
+pub fn follow(path: &Path, framing: &Framing, mut send: impl FnMut(Unit, bool)) -> Result<()> {
+    let mut from_start = false;
+    let mut waiting = false;
+    loop {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => {
+                // Not there yet, or gone mid-rotation: wait for it like tail -F
+                if !waiting {
+                    eprintln!("vecgrep: {}: {}; waiting for it", path.display(), err);
+                    waiting = true;
+                }
+                from_start = true;
+                thread::sleep(POLL);
+                continue;
+            }
+        };
+        if waiting {
+            eprintln!("vecgrep: {} has appeared; following it", path.display());
+            waiting = false;
+        }
+
+        let mut tail = Tail::new(file, path)?;
+        let (offset, line) = if from_start {
+            (0, 1)
+        } else {
+            tail.skip_to_end()
+                .with_context(|| format!("failed reading {}", path.display()))?
+        };
+        let mut reader = RecordReader::new(BufReader::new(tail), framing).starting_at(offset, line);
+        let mut reopened = true;
+        while let Some(unit) = reader.next_record()? {
+            send(unit, std::mem::take(&mut reopened));
+        }
+        // The tail ended because the file was truncated or replaced
+        from_start = true;
+    }
+}
+
+/// A file that never ends: at its end, reads wait for more data. They return end of file
+/// only once the file at the path was truncated or replaced by another one.
+struct Tail {
+    file: File,
+    path: PathBuf,
+    id: Option<(u64, u64)>,
+    pos: u64,
+}
+
+impl Tail {
+    fn new(file: File, path: &Path) -> Result<Self> {
+        let meta = file
+            .metadata()
+            .with_context(|| format!("failed reading {}", path.display()))?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            id: file_id(&meta),
+            pos: 0,
+        })
+    }
+
+    /// Move past the last complete line, so an unfinished one is read once it's done.
+    /// Returns the byte offset and line number reading continues from.
+    fn skip_to_end(&mut self) -> io::Result<(u64, usize)> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut end = self.file.seek(SeekFrom::End(0))?;
+        let resume = loop {
+            if end == 0 {
+                break 0;
+            }
+            let start = end.saturating_sub(buf.len() as u64);
+            let block = &mut buf[..(end - start) as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            self.file.read_exact(block)?;
+            if let Some(i) = block.iter().rposition(|&b| b == b'\n') {
+                break start + i as u64 + 1;
+            }
+            end = start;
+        };
+
+        // Line numbers are the file's own, so count the lines before that point
+        self.file.seek(SeekFrom::Start(0))?;
+        let (mut left, mut line) = (resume, 1);
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            let block = &mut buf[..n];
+            self.file.read_exact(block)?;
+            line += block.iter().filter(|&&b| b == b'\n').count();
+            left -= block.len() as u64;
+        }
+        self.pos = self.file.seek(SeekFrom::Start(resume))?;
+        Ok((resume, line))
+    }
+}
+
+impl Read for Tail {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            // At the end: anything that isn't news leaves the file open, including the
+            // path being gone for a moment during rotation
+            match fs::metadata(&self.path) {
+                Ok(meta) if file_id(&meta) != self.id => {
+                    eprintln!(
+                        "vecgrep: {} has been replaced; following the new file",
+                        self.path.display()
+                    );
+                    return Ok(0);
+                }
+                Ok(meta) if meta.len() < self.pos => {
+                    eprintln!("vecgrep: {}: file truncated", self.path.display());
+                    return Ok(0);
+                }
+                _ => thread::sleep(POLL),
+            }
+        }
+    }
+}
+
+/// Device and inode, which change when a file is rotated
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+/// Without inodes only truncation is noticed
+#[cfg(not(unix))]
+fn file_id(_: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where following a file with this content starts: byte offset and line number
+    fn resume(name: &str, content: &[u8]) -> (u64, usize) {
+        let path = std::env::temp_dir().join(format!("vecgrep-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let mut tail = Tail::new(File::open(&path).unwrap(), &path).unwrap();
+        let (offset, line) = tail.skip_to_end().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(tail.pos, offset);
+        (offset, line)
+    }
+
+    #[test]
+    fn skips_to_after_the_last_complete_line() {
+        assert_eq!(resume("empty", b""), (0, 1));
+        assert_eq!(resume("unfinished", b"no newline yet"), (0, 1));
+        assert_eq!(resume("lines", b"a\nb\n"), (4, 3));
+        assert_eq!(resume("partial", b"a\nb\npart"), (4, 3));
+    }
+
+    #[test]
+    fn scans_back_across_blocks() {
+        let mut content = b"first\n".repeat(20_000);
+        content.resize(200_000, b'x');
+        assert_eq!(resume("long", &content), (120_000, 20_001));
+    }
+}
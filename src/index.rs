@@ -0,0 +1,484 @@
+use crate::ann::{self, Ann};
+use crate::input;
+use anyhow::{bail, ensure, Context, Result};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &[u8; 4] = b"VGIX";
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 72;
+
+// Layout (little endian):
+//   header   magic, version, dim, reserved, total_lines, file_count,
+//            vectors_at, offsets_at, files_at, model_at, ann_at
+//   vectors  total_lines x dim f32, normalized
+//   offsets  total_lines x u64 byte offset of each line in its file
+//   files    per file: path, mtime, size, first_line, line_count
+//   model    model ID the vectors were produced with
+//   ann      optional IVF-PQ structure (see ann.rs); ann_at is 0 when absent
+//
+// Vectors come first so they can be streamed out while building; the header is
+// patched once all sections are written.
+
+/// One indexed file and where its lines live in the vector section
+pub struct FileEntry {
+    pub path: String,
+    /// Modification time as (seconds, nanoseconds) since the epoch
+    pub mtime: (u64, u32),
+    pub size: u64,
+    pub first_line: usize,
+    pub line_count: usize,
+}
+
+impl FileEntry {
+    /// True if the file on disk still matches what was indexed
+    pub fn is_fresh(&self, meta: &Metadata) -> bool {
+        file_stamp(meta) == self.mtime && meta.len() == self.size
+    }
+}
+
+/// A memory-mapped index file
+pub struct Index {
+    mmap: Mmap,
+    pub model_id: String,
+    pub dim: usize,
+    pub files: Vec<FileEntry>,
+    total_lines: usize,
+    vectors_at: usize,
+    offsets_at: usize,
+    ann_at: usize,
+}
+
+/// Counts reported after `vecgrep index`
+pub struct BuildStats {
+    pub files: usize,
+    pub reused_files: usize,
+    pub embedded_lines: usize,
+    pub total_lines: usize,
+}
+
+pub fn file_stamp(meta: &Metadata) -> (u64, u32) {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |d| (d.as_secs(), d.subsec_nanos()))
+}
+
+impl Index {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        // Safety: the index is only replaced via rename, never modified in place
+        let mmap = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("failed to map {}", path.display()))?;
+
+        let mut r = Reader { buf: &mmap, pos: 0 };
+        ensure!(
+            r.bytes(4)? == MAGIC,
+            "{} is not a vecgrep index",
+            path.display()
+        );
+        let version = r.u32()?;
+        ensure!(version == VERSION, "unsupported index version {}", version);
+        let dim = r.u32()? as usize;
+        let _reserved = r.u32()?;
+        let total_lines = r.u64()? as usize;
+        let file_count = r.u64()? as usize;
+        let vectors_at = r.u64()? as usize;
+        let offsets_at = r.u64()? as usize;
+        let files_at = r.u64()? as usize;
+        let model_at = r.u64()? as usize;
+        let ann_at = r.u64()? as usize;
+        // Checked so that huge values from a corrupt header can't wrap past the bounds
+        let end = |at: usize, count: usize, size: usize| count.checked_mul(size)?.checked_add(at);
+        ensure!(
+            end(vectors_at, total_lines, dim * 4).is_some_and(|end| end <= offsets_at)
+                && end(offsets_at, total_lines, 8).is_some_and(|end| end <= files_at)
+                && files_at <= mmap.len()
+                && ann_at <= mmap.len(),
+            "corrupt index {}",
+            path.display()
+        );
+
+        r.pos = files_at;
+        // A bogus count fails on the reads below rather than on allocation
+        let mut files = Vec::with_capacity(file_count.min(mmap.len() - files_at));
+        for _ in 0..file_count {
+            let len = r.u32()? as usize;
+            let path = String::from_utf8_lossy(r.bytes(len)?).into_owned();
+            let mtime = (r.u64()?, r.u32()?);
+            let size = r.u64()?;
+            let first_line = r.u64()? as usize;
+            let line_count = r.u64()? as usize;
+            ensure!(
+                first_line
+                    .checked_add(line_count)
+                    .is_some_and(|end| end <= total_lines),
+                "corrupt index file table"
+            );
+            files.push(FileEntry {
+                path,
+                mtime,
+                size,
+                first_line,
+                line_count,
+            });
+        }
+
+        r.pos = model_at;
+        let len = r.u32()? as usize;
+        let model_id = String::from_utf8_lossy(r.bytes(len)?).into_owned();
+
+        Ok(Self {
+            model_id,
+            dim,
+            files,
+            total_lines,
+            vectors_at,
+            offsets_at,
+            ann_at,
+            mmap,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.total_lines
+    }
+
+    /// Cosine similarity of line `i` against a normalized query
+    pub fn dot(&self, i: usize, query: &[f32]) -> f32 {
+        self.vector_bytes(i..i + 1)
+            .chunks_exact(4)
+            .zip(query)
+            .map(|(b, q)| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * q)
+            .sum()
+    }
+
+    /// Decoded vector of line `i`
+    pub fn vector(&self, i: usize) -> Vec<f32> {
+        self.vector_bytes(i..i + 1)
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    /// The approximate nearest-neighbour structure, if the index was built with one
+    pub fn ann(&self) -> Option<Result<Ann<'_>>> {
+        (self.ann_at != 0)
+            .then(|| Ann::parse(&self.mmap[self.ann_at..], self.dim, self.total_lines))
+    }
+
+    /// Byte offset of line `i` within its file
+    pub fn offset(&self, i: usize) -> u64 {
+        let at = self.offsets_at + i * 8;
+        u64::from_le_bytes(self.mmap[at..at + 8].try_into().unwrap())
+    }
+
+    /// Raw little-endian vectors for a range of lines
+    fn vector_bytes(&self, lines: std::ops::Range<usize>) -> &[u8] {
+        let row = self.dim * 4;
+        &self.mmap[self.vectors_at + lines.start * row..self.vectors_at + lines.end * row]
+    }
+}
+
+/// Build (or incrementally update) the index at `out` from `files`.
+/// Files whose mtime and size match the previous index keep their vectors; the rest are
+/// read and embedded in batches of roughly `batch_lines` lines across files.
+/// With `with_ann` an IVF-PQ structure is trained over all vectors afterwards.
+pub fn build(
+    out: &Path,
+    model_id: &str,
+    files: &[PathBuf],
+    batch_lines: usize,
+    with_ann: bool,
+    encode: impl Fn(&[String]) -> Vec<Vec<f32>>,
+) -> Result<BuildStats> {
+    let previous = if out.exists() {
+        match Index::open(out) {
+            Ok(index) if index.model_id == model_id => Some(index),
+            Ok(index) => {
+                eprintln!(
+                    "vecgrep: index was built with model {}, rebuilding for {}",
+                    index.model_id, model_id
+                );
+                None
+            }
+            Err(err) => {
+                eprintln!("vecgrep: rebuilding unreadable index: {:#}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    // Keep an existing ANN structure up to date even without --ann
+    let with_ann = with_ann || previous.as_ref().is_some_and(|index| index.ann_at != 0);
+    let previous_files: HashMap<&str, &FileEntry> = previous
+        .iter()
+        .flat_map(|index| index.files.iter().map(|f| (f.path.as_str(), f)))
+        .collect();
+
+    let mut tmp_name = OsString::from(out.as_os_str());
+    tmp_name.push(".tmp");
+    let tmp = PathBuf::from(tmp_name);
+    let mut w = Writer {
+        out: BufWriter::new(
+            File::create(&tmp).with_context(|| format!("failed to create {}", tmp.display()))?,
+        ),
+        dim: previous.as_ref().map(|index| index.dim),
+        lines: 0,
+        offsets: Vec::new(),
+    };
+    w.out.write_all(&[0u8; HEADER_LEN])?;
+
+    let mut entries: Vec<FileEntry> = Vec::new();
+    let mut pending: Vec<(usize, Vec<String>, Vec<u64>)> = Vec::new();
+    let mut pending_lines = 0usize;
+    let mut stats = BuildStats {
+        files: 0,
+        reused_files: 0,
+        embedded_lines: 0,
+        total_lines: 0,
+    };
+
+    // Never index the index itself; only the file at that path, not others sharing its name
+    let own = [fs::canonicalize(out).ok(), fs::canonicalize(&tmp).ok()];
+    for path in files {
+        if *path == tmp
+            || ([out.file_name(), tmp.file_name()].contains(&path.file_name())
+                && own.contains(&fs::canonicalize(path).ok()))
+        {
+            continue;
+        }
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(err) => {
+                eprintln!("vecgrep: {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        let display = path.display().to_string();
+        let mut entry = FileEntry {
+            mtime: file_stamp(&meta),
+            size: meta.len(),
+            first_line: 0,
+            line_count: 0,
+            path: display,
+        };
+
+        let reusable = previous_files
+            .get(entry.path.as_str())
+            .filter(|old| old.is_fresh(&meta));
+        if let (Some(old), Some(index)) = (reusable, &previous) {
+            let first = old.first_line;
+            let range = first..first + old.line_count;
+            entry.first_line = w.lines;
+            entry.line_count = old.line_count;
+            w.out.write_all(index.vector_bytes(range.clone()))?;
+            w.offsets.extend(range.map(|i| index.offset(i)));
+            w.lines += old.line_count;
+            stats.reused_files += 1;
+        } else {
+            let Some((lines, offsets)) = input::read_file(path) else {
+                continue;
+            };
+            pending_lines += lines.len();
+            pending.push((entries.len(), lines, offsets));
+        }
+        entries.push(entry);
+
+        if pending_lines >= batch_lines {
+            stats.embedded_lines += w.flush_pending(&mut pending, &mut entries, &encode)?;
+            pending_lines = 0;
+        }
+    }
+    stats.embedded_lines += w.flush_pending(&mut pending, &mut entries, &encode)?;
+
+    // Trailing sections, then patch the header with their positions
+    let dim = w.dim.unwrap_or(0);
+    let vectors_at = HEADER_LEN as u64;
+    let offsets_at = vectors_at + (w.lines * dim * 4) as u64;
+    for off in &w.offsets {
+        w.out.write_all(&off.to_le_bytes())?;
+    }
+    let files_at = offsets_at + (w.offsets.len() * 8) as u64;
+    let mut table = Vec::new();
+    for entry in &entries {
+        table.extend_from_slice(&(entry.path.len() as u32).to_le_bytes());
+        table.extend_from_slice(entry.path.as_bytes());
+        table.extend_from_slice(&entry.mtime.0.to_le_bytes());
+        table.extend_from_slice(&entry.mtime.1.to_le_bytes());
+        table.extend_from_slice(&entry.size.to_le_bytes());
+        table.extend_from_slice(&(entry.first_line as u64).to_le_bytes());
+        table.extend_from_slice(&(entry.line_count as u64).to_le_bytes());
+    }
+    w.out.write_all(&table)?;
+    let model_at = files_at + table.len() as u64;
+    w.out.write_all(&(model_id.len() as u32).to_le_bytes())?;
+    w.out.write_all(model_id.as_bytes())?;
+
+    let mut fields = [
+        w.lines as u64,
+        entries.len() as u64,
+        vectors_at,
+        offsets_at,
+        files_at,
+        model_at,
+        0,
+    ];
+    let mut file = w.out.into_inner().map_err(|e| e.into_error())?;
+    write_header(&mut file, dim, &fields)?;
+    drop(previous);
+
+    // The ANN structure is trained from the finished vector section and appended
+    if with_ann && w.lines > 0 {
+        let section = ann::build(&Index::open(&tmp)?);
+        fields[6] = file.seek(SeekFrom::End(0))?;
+        file.write_all(&section)?;
+        write_header(&mut file, dim, &fields)?;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, out).with_context(|| format!("failed to write {}", out.display()))?;
+
+    stats.files = entries.len();
+    stats.total_lines = w.lines;
+    Ok(stats)
+}
+
+fn write_header(file: &mut File, dim: usize, fields: &[u64; 7]) -> Result<()> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(dim as u32).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    for field in fields {
+        header.extend_from_slice(&field.to_le_bytes());
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+    Ok(())
+}
+
+/// Streams vectors into the index file while tracking line offsets
+struct Writer {
+    out: BufWriter<File>,
+    dim: Option<usize>,
+    lines: usize,
+    offsets: Vec<u64>,
+}
+
+impl Writer {
+    /// Embed all pending files in one call and append their vectors.
+    /// Returns the number of lines embedded.
+    fn flush_pending(
+        &mut self,
+        pending: &mut Vec<(usize, Vec<String>, Vec<u64>)>,
+        entries: &mut [FileEntry],
+        encode: &impl Fn(&[String]) -> Vec<Vec<f32>>,
+    ) -> Result<usize> {
+        let texts: Vec<String> = pending
+            .iter()
+            .flat_map(|(_, lines, _)| lines.iter().cloned())
+            .collect();
+        if texts.is_empty() {
+            pending.clear();
+            return Ok(0);
+        }
+        let vectors = encode(&texts);
+        let dim = *self.dim.get_or_insert(vectors[0].len());
+
+        let mut vectors = vectors.into_iter();
+        for (entry_idx, lines, offsets) in pending.drain(..) {
+            entries[entry_idx].first_line = self.lines;
+            entries[entry_idx].line_count = lines.len();
+            for v in vectors.by_ref().take(lines.len()) {
+                if v.len() != dim {
+                    bail!("embedding dimension changed from {} to {}", dim, v.len());
+                }
+                for x in v {
+                    self.out.write_all(&x.to_le_bytes())?;
+                }
+            }
+            self.offsets.extend(offsets);
+            self.lines += lines.len();
+        }
+        Ok(texts.len())
+    }
+}
+
+/// Bounds-checked little-endian reader over the mapped index
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.buf.len());
+        let end = end.context("truncated index")?;
+        let out = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vecgrep-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn skips_only_the_index_itself() {
+        let dir = scratch("index-self");
+        let out = dir.join("corpus.vgi");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/corpus.vgi"), "a line\n").unwrap();
+        fs::write(&out, "not an index yet\n").unwrap();
+        let files = [out.clone(), dir.join("sub/corpus.vgi")];
+        let stats = build(&out, "test", &files, 16, false, |texts| {
+            texts.iter().map(|_| vec![1.0, 0.0]).collect()
+        })
+        .unwrap();
+        let index = Index::open(&out).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(index.files.len(), 1);
+        assert!(index.files[0].path.ends_with("sub/corpus.vgi"));
+    }
+
+    #[test]
+    fn overflowing_header_is_corrupt() {
+        let dir = scratch("index-overflow");
+        let path = dir.join("corpus.vgi");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[0u8; HEADER_LEN]).unwrap();
+        // total_lines * dim * 4 wraps to 0 and would pass an unchecked bounds test
+        let total_lines = 1u64 << 62;
+        let at = HEADER_LEN as u64;
+        write_header(&mut file, 1, &[total_lines, 0, at, at, at, at, 0]).unwrap();
+        drop(file);
+        let err = Index::open(&path).err().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(err.to_string().starts_with("corrupt index"));
+    }
+}
@@ -0,0 +1,309 @@
+use crate::cli::WalkArgs;
+use crate::code::{self, Lang};
+use crate::record::{Framing, RecordReader, Unit};
+use anyhow::{bail, Context, Result};
+use ignore::overrides::OverrideBuilder;
+use ignore::types::TypesBuilder;
+use ignore::WalkBuilder;
+use std::fs;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// One input (a file or stdin) and the slice of corpus lines it contributed
+pub struct Source {
+    /// Display path, `None` when reading stdin
+    pub path: Option<String>,
+    /// Indices into `Corpus::lines` belonging to this source
+    pub range: Range<usize>,
+    /// Header row with --csv/--tsv, kept out of `range`
+    pub header: Option<String>,
+}
+
+/// All input lines gathered up front, so they can be encoded in one batched call.
+/// In record mode each entry is a whole record, its lines joined with '\n'.
+#[derive(Default)]
+pub struct Corpus {
+    pub lines: Vec<String>,
+    /// Byte offset of each line within its source
+    pub offsets: Vec<u64>,
+    /// 1-based number of each entry's (first) line within its source
+    pub line_numbers: Vec<usize>,
+    pub sources: Vec<Source>,
+}
+
+impl Corpus {
+    pub fn push_source(&mut self, path: Option<String>, lines: Vec<String>, offsets: Vec<u64>) {
+        let start = self.lines.len();
+        self.line_numbers.extend(1..=lines.len());
+        self.lines.extend(lines);
+        self.offsets.extend(offsets);
+        let end = self.lines.len();
+        self.sources.push(Source {
+            path,
+            range: start..end,
+            header: None,
+        });
+    }
+
+    /// Add a source's lines or records; with --csv/--tsv the first one is its header
+    pub fn push_units(&mut self, path: Option<String>, units: Vec<Unit>, framing: &Framing) {
+        let mut units = units.into_iter();
+        let header = match framing {
+            Framing::Table(_) => units.next().map(|unit| unit.text),
+            _ => None,
+        };
+        let start = self.lines.len();
+        for unit in units {
+            self.lines.push(unit.text);
+            self.offsets.push(unit.offset);
+            self.line_numbers.push(unit.line_number);
+        }
+        let end = self.lines.len();
+        self.sources.push(Source {
+            path,
+            range: start..end,
+            header,
+        });
+    }
+
+    /// Drop the sources `keep` rejects, along with their lines
+    pub fn retain_sources(&mut self, mut keep: impl FnMut(&Source) -> bool) {
+        let old = std::mem::take(self);
+        let mut entries = old.lines.into_iter().zip(old.offsets).zip(old.line_numbers);
+        for source in old.sources {
+            let lines: Vec<_> = entries.by_ref().take(source.range.len()).collect();
+            if !keep(&source) {
+                continue;
+            }
+            let start = self.lines.len();
+            for ((line, offset), line_number) in lines {
+                self.lines.push(line);
+                self.offsets.push(offset);
+                self.line_numbers.push(line_number);
+            }
+            self.sources.push(Source {
+                range: start..self.lines.len(),
+                ..source
+            });
+        }
+    }
+}
+
+/// Read stdin when no paths are given, otherwise every file under `paths`,
+/// split into lines or records by `framing`.
+/// Files named explicitly are always read; filters only apply to directory walks.
+pub fn gather(paths: &[PathBuf], walk: &WalkArgs, framing: &Framing) -> Result<Corpus> {
+    let mut corpus = Corpus::default();
+    if paths.is_empty() {
+        read_stdin(&mut corpus, None, framing)?;
+        return Ok(corpus);
+    }
+
+    for path in paths {
+        if path.as_os_str() == "-" {
+            read_stdin(&mut corpus, Some("(standard input)".to_string()), framing)?;
+            continue;
+        }
+        for file in expand(path, walk)? {
+            // NUL bytes are separators, not a sign of a binary file, with --null-data
+            let allow_nul = matches!(framing, Framing::Null);
+            if let Some(bytes) = read_bytes(&file, allow_nul) {
+                let units = split_units(&bytes, framing, Some(&file))?;
+                corpus.push_units(Some(file.display().to_string()), units, framing);
+            }
+        }
+    }
+    Ok(corpus)
+}
+
+/// List the files `paths` refer to, walking directories with the same filters as `gather`
+pub fn files(paths: &[PathBuf], walk: &WalkArgs) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.as_os_str() == "-" {
+            bail!("stdin can't be used here; pass files or directories");
+        }
+        files.extend(expand(path, walk)?);
+    }
+    Ok(files)
+}
+
+/// True when any input will be read from stdin
+pub fn reads_stdin(paths: &[PathBuf]) -> bool {
+    paths.is_empty() || paths.iter().any(|p| p.as_os_str() == "-")
+}
+
+/// Read a file's lines and their byte offsets. Unreadable files are reported and
+/// binary files skipped; both yield `None`.
+pub fn read_file(path: &Path) -> Option<(Vec<String>, Vec<u64>)> {
+    read_bytes(path, false).map(|bytes| split_lines(&bytes))
+}
+
+fn read_bytes(path: &Path, allow_nul: bool) -> Option<Vec<u8>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("vecgrep: {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    // Skip binary files; embedding them only produces noise
+    if !allow_nul && bytes.contains(&0) {
+        return None;
+    }
+    Some(bytes)
+}
+
+/// Split a whole input into lines, records or code units (`path` picks the language)
+fn split_units(bytes: &[u8], framing: &Framing, path: Option<&Path>) -> Result<Vec<Unit>> {
+    if let Framing::Code = framing {
+        let (lines, offsets) = split_lines(bytes);
+        return Ok(code::split(&lines, &offsets, Lang::detect(path)));
+    }
+    let mut reader = RecordReader::new(bytes, framing);
+    let mut units = Vec::new();
+    while let Some(unit) = reader.next_record()? {
+        units.push(unit);
+    }
+    Ok(units)
+}
+
+/// Split like `str::lines` (dropping `\n` / `\r\n`), also returning each line's
+/// byte offset. Invalid UTF-8 is replaced rather than rejected.
+pub fn split_lines(bytes: &[u8]) -> (Vec<String>, Vec<u64>) {
+    let mut lines = Vec::new();
+    let mut offsets = Vec::new();
+    let mut start = 0usize;
+    while start < bytes.len() {
+        let end = bytes[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |p| start + p);
+        lines.push(line_text(&bytes[start..end]));
+        offsets.push(start as u64);
+        start = end + 1;
+    }
+    (lines, offsets)
+}
+
+/// Lines starting at `offsets` (as found by `split_lines`), each up to its newline;
+/// how `search` reads matched lines back from an indexed file
+pub fn lines_at(bytes: &[u8], offsets: &[u64]) -> Vec<String> {
+    offsets
+        .iter()
+        .map(|&start| {
+            let line = bytes.get(start as usize..).unwrap_or_default();
+            let end = line.iter().position(|&b| b == b'\n').unwrap_or(line.len());
+            line_text(&line[..end])
+        })
+        .collect()
+}
+
+/// Decode one raw line, dropping a trailing `\r`
+pub fn line_text(raw: &[u8]) -> String {
+    let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+    String::from_utf8_lossy(raw).into_owned()
+}
+
+fn read_stdin(corpus: &mut Corpus, path: Option<String>, framing: &Framing) -> Result<()> {
+    let mut bytes = Vec::new();
+    io::stdin()
+        .lock()
+        .read_to_end(&mut bytes)
+        .context("failed reading stdin")?;
+    corpus.push_units(path, split_units(&bytes, framing, None)?, framing);
+    Ok(())
+}
+
+/// A file path as-is, or the files under a directory
+fn expand(path: &Path, walk: &WalkArgs) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .hidden(!walk.hidden)
+        .ignore(!walk.no_ignore)
+        .git_ignore(!walk.no_ignore)
+        .git_global(!walk.no_ignore)
+        .git_exclude(!walk.no_ignore)
+        .parents(!walk.no_ignore)
+        // Sort for deterministic output order across runs
+        .sort_by_file_name(|a, b| a.cmp(b));
+
+    if !walk.globs.is_empty() {
+        let mut overrides = OverrideBuilder::new(path);
+        for glob in &walk.globs {
+            overrides
+                .add(glob)
+                .with_context(|| format!("invalid glob: {}", glob))?;
+        }
+        builder.overrides(overrides.build()?);
+    }
+    if !walk.types.is_empty() {
+        let mut types = TypesBuilder::new();
+        types.add_defaults();
+        for name in &walk.types {
+            types.select(name);
+        }
+        builder.types(types.build().context("invalid --type")?);
+    }
+
+    let mut files = Vec::new();
+    for entry in builder.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                eprintln!("vecgrep: {}", err);
+                continue;
+            }
+        };
+        // Like ripgrep, symlinks found while walking are not followed
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexed_lines_read_back_as_indexed() {
+        // `index` stores split_lines offsets, `search` slices the file by them
+        for text in [
+            "first\nsecond\nlast\n",
+            "first\nsecond\nlast",
+            "crlf\r\nlines\r\n",
+            "\nblank\n\nlines\n",
+        ] {
+            let (lines, offsets) = split_lines(text.as_bytes());
+            assert_eq!(lines_at(text.as_bytes(), &offsets), lines, "{:?}", text);
+        }
+        let (lines, _) = split_lines(b"a\nb\n");
+        assert_eq!(lines, ["a", "b"]);
+    }
+
+    #[test]
+    fn dropped_sources_take_their_lines() {
+        let mut corpus = Corpus::default();
+        for (path, text) in [("a", "a1\na2\n"), ("b", "b1\n"), ("c", "c1\nc2\n")] {
+            let (lines, offsets) = split_lines(text.as_bytes());
+            corpus.push_source(Some(path.to_string()), lines, offsets);
+        }
+        corpus.retain_sources(|source| source.path.as_deref() != Some("b"));
+        assert_eq!(corpus.lines, ["a1", "a2", "c1", "c2"]);
+        assert_eq!(corpus.offsets, [0, 3, 0, 3]);
+        assert_eq!(corpus.line_numbers, [1, 2, 1, 2]);
+        let sources: Vec<_> = corpus
+            .sources
+            .iter()
+            .map(|source| (source.path.as_deref().unwrap(), source.range.clone()))
+            .collect();
+        assert_eq!(sources, [("a", 0..2), ("c", 2..4)]);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+// Okapi BM25 parameters
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+/// Term statistics of the scored lines for BM25. Lines can be added as they arrive,
+/// so stream mode scores each line against everything seen so far.
+#[derive(Default)]
+pub struct Bm25 {
+    /// Number of lines containing each term
+    df: HashMap<String, usize>,
+    lines: usize,
+    total_terms: usize,
+}
+
+impl Bm25 {
+    pub fn add(&mut self, text: &str) {
+        let terms = terms(text);
+        self.lines += 1;
+        self.total_terms += terms.len();
+        let unique: HashSet<String> = terms.into_iter().collect();
+        for term in unique {
+            *self.df.entry(term).or_default() += 1;
+        }
+    }
+
+    /// BM25 of `text` for each query (given as its terms), divided by the query's upper
+    /// bound (every term present with unbounded frequency) so scores fall in [0, 1)
+    pub fn score(&self, queries: &[Vec<String>], text: &str) -> Vec<f32> {
+        let terms = terms(text);
+        let avg_len = self.total_terms as f32 / self.lines.max(1) as f32;
+        let norm = K1 * (1.0 - B + B * terms.len() as f32 / avg_len.max(1.0));
+        queries
+            .iter()
+            .map(|query| {
+                let mut score = 0.0;
+                let mut bound = 0.0;
+                for term in query {
+                    let idf = self.idf(term);
+                    let tf = terms.iter().filter(|t| *t == term).count() as f32;
+                    score += idf * tf * (K1 + 1.0) / (tf + norm);
+                    bound += idf * (K1 + 1.0);
+                }
+                if bound > 0.0 {
+                    score / bound
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    fn idf(&self, term: &str) -> f32 {
+        let n = self.lines as f32;
+        let df = self.df.get(term).copied().unwrap_or(0) as f32;
+        (1.0 + (n - df + 0.5) / (df + 0.5)).ln()
+    }
+}
+
+/// Parse a blend weight in [0, 1]
+pub fn parse_weight(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(w) if (0.0..=1.0).contains(&w) => Ok(w),
+        _ => Err(format!("expected a weight between 0 and 1, got '{}'", s)),
+    }
+}
+
+/// Lowercased words, so `E1234` in a query matches `e1234` in a line
+pub fn terms(text: &str) -> Vec<String> {
+    words(text)
+        .into_iter()
+        .map(|span| text[span].to_lowercase())
+        .collect()
+}
+
+/// Blend semantic and lexical scores per query: (1 - weight) * cosine + weight * lexical
+pub fn blend(row: &mut [f32], lexical: &[f32], weight: f32) {
+    for (s, l) in row.iter_mut().zip(lexical) {
+        *s = (1.0 - weight) * *s + weight * l;
+    }
+}
+
+/// Byte ranges of runs of alphanumeric characters (and '_')
+pub fn words(line: &str) -> Vec<Range<usize>> {
+    let mut out = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        let is_word = c.is_alphanumeric() || c == '_';
+        match (is_word, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                out.push(s..i);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        out.push(s..line.len());
+    }
+    out
+}
@@ -1,59 +1,49 @@
-use anyhow::{Context, Result};
-use clap::{ArgAction, Parser};
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
 use model2vec_rs::model::StaticModel;
 use rayon::prelude::*;
 use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::fs;
+use std::io;
 use std::io::IsTerminal;
-use std::io::{self, BufRead};
 
-#[derive(Parser, Debug)]
-#[command(
-    name = "vecgrep",
-    version,
-    about = "Semantic grep powered by model2vec-rs"
-)]
-struct Cli {
-    /// Query string to search for semantically similar lines
-    query: String,
-
-    /// Similarity threshold in [0,1]. Matches below are filtered out
-    #[arg(short = 't', long = "threshold", default_value_t = 0.6)]
-    threshold: f32,
-
-    /// Number of context lines to show after each match (like grep -A)
-    #[arg(short = 'A', default_value_t = 0)]
-    after: usize,
-
-    /// Number of context lines to show before each match (like grep -B)
-    #[arg(short = 'B', default_value_t = 0)]
-    before: usize,
-
-    /// Model ID from Hugging Face or local path (env: VECGREP_MODEL)
-    #[arg(
-        short = 'm',
-        long = "model",
-        env = "VECGREP_MODEL",
-        default_value = "minishlab/potion-base-8M"
-    )]
-    model: String,
-
-    /// Hide similarity score for each matching line
-    #[arg(long = "hide-scores", action = ArgAction::SetTrue)]
-    hide_scores: bool,
-
-    /// Return top-N most similar lines (disables threshold; not allowed with --stream)
-    #[arg(long = "top", conflicts_with = "stream")]
-    top: Option<usize>,
-
-    /// Batch size for encoding (tune perf / memory)
-    #[arg(long = "batch-size", default_value_t = 1024)]
-    batch_size: usize,
-
-    /// Stream mode: process and print incrementally for non-stopping input
-    #[arg(long = "stream", action = ArgAction::SetTrue)]
-    stream: bool,
-}
+mod ann;
+mod cache;
+mod chunk;
+mod cli;
+mod code;
+mod color;
+mod explain;
+mod fields;
+mod filter;
+mod follow;
+mod index;
+mod input;
+mod lexical;
+mod output;
+mod preprocess;
+mod query;
+mod queue;
+mod record;
+mod stats;
+mod stream;
+mod table;
+
+use chunk::Chunker;
+use cli::{CacheAction, CacheArgs, Cli, Command, IndexArgs, MatchArgs, SearchArgs};
+use explain::Contribution;
+use fields::Extractor;
+use filter::LineFilter;
+use input::Corpus;
+use output::Results;
+use preprocess::Preprocessor;
+use query::Queries;
+use record::Framing;
+use stats::Distribution;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::PathBuf;
+use table::Columns;
 
 fn normalize(v: &mut [f32]) {
     let sum_sq: f32 = v.iter().map(|x| x * x).sum();
@@ -70,62 +60,29 @@ fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     dot
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    // Load model (normalize embeddings enabled by default config unless overridden)
-    let model = StaticModel::from_pretrained(&cli.model, None, None, None)
-        .context("failed to load model")?;
-
-    // Encode query once
-    let mut query_vec = model.encode(std::slice::from_ref(&cli.query))[0].clone();
-    normalize(&mut query_vec);
-
-    if cli.stream {
-        run_stream(&cli, &model, &query_vec)?;
-        return Ok(());
-    }
-
-    // If reading from piped stdin without --stream, print a hint once
-    if !io::stdin().is_terminal() {
-        eprintln!(
-            "reading from stdin until EOF. For endless inputs (e.g., tail -f), use --stream to process incrementally"
-        );
-    }
-
-    // Read all stdin lines first to preserve order for context windows
-    let stdin = io::stdin();
-    let input_lines: Vec<String> = stdin
-        .lock()
-        .lines()
-        .collect::<Result<_, _>>()
-        .context("failed reading stdin")?;
-
-    // Encode all lines in batches; model2vec-rs exposes encode_with_args for batch tuning
-    let embeddings = model.encode_with_args(&input_lines, None, cli.batch_size);
-
-    // Normalize each embedding for cosine similarity
-    let norm_embeddings: Vec<Vec<f32>> = embeddings
+/// Encode lines in batches and normalize each embedding for cosine similarity
+fn encode_normalized(model: &StaticModel, lines: &[String], batch_size: usize) -> Vec<Vec<f32>> {
+    // model2vec-rs exposes encode_with_args for batch tuning
+    model
+        .encode_with_args(lines, None, batch_size)
         .into_par_iter()
         .map(|mut v| {
             normalize(&mut v);
             v
         })
-        .collect();
-
-    // Compute similarity per line once
-    let scores: Vec<f32> = norm_embeddings
-        .par_iter()
-        .map(|v| cosine_similarity(&query_vec, v))
-        .collect();
+        .collect()
+}
 
-    // Determine matches either by threshold or by top-N selection
-    let mut is_match = vec![false; input_lines.len()];
-    let selection_summary: String;
-    if let Some(top_n) = cli.top {
-        let n = top_n.min(scores.len());
+/// Determine matches either by threshold or by top-N selection.
+/// Lines flagged in `excluded` (empty for none) are never selected.
+fn select_matches(scores: &[f32], excluded: &[bool], args: &MatchArgs) -> (Vec<bool>, String) {
+    let is_excluded = |i: usize| excluded.get(i).copied().unwrap_or(false);
+    let mut is_match = vec![false; scores.len()];
+    let mut selection_summary: String;
+    if let Some(top_n) = args.top {
         // Build index list and select top-N by score (descending)
-        let mut indices: Vec<usize> = (0..scores.len()).collect();
+        let mut indices: Vec<usize> = (0..scores.len()).filter(|&i| !is_excluded(i)).collect();
+        let n = top_n.min(indices.len());
         indices.sort_by(|&i, &j| scores[j].partial_cmp(&scores[i]).unwrap_or(Ordering::Equal));
         for &idx in indices.iter().take(n) {
             is_match[idx] = true;
@@ -141,10 +98,10 @@ fn main() -> Result<()> {
         );
     } else {
         // Identify matches above threshold
-        let threshold = cli.threshold;
+        let threshold = args.threshold;
         let mut selection_count: usize = 0;
         for (idx, &score) in scores.iter().enumerate() {
-            if score >= threshold {
+            if score >= threshold && !is_excluded(idx) {
                 is_match[idx] = true;
                 selection_count += 1;
             }
@@ -155,149 +112,480 @@ fn main() -> Result<()> {
             format!("matches: {} (threshold {:.2})", selection_count, threshold)
         };
     }
+    let dropped = excluded.iter().filter(|&&e| e).count();
+    if dropped > 0 {
+        selection_summary.push_str(&format!("; {} lines excluded by --not", dropped));
+    }
+    (is_match, selection_summary)
+}
 
-    // Print matches with context, merging overlapping windows
-    let mut i = 0usize;
-    while i < input_lines.len() {
-        if !is_match[i] {
-            i += 1;
-            continue;
-        }
+/// Word contributions of the matching lines with --explain, indexed like `lines`.
+/// Each line is explained by the query it scored best against.
+fn explain_matches(
+    args: &MatchArgs,
+    model: &StaticModel,
+    batch_size: usize,
+    queries: &Queries,
+    lines: &[String],
+    is_match: &[bool],
+    query_scores: &[f32],
+) -> Vec<Vec<Contribution>> {
+    if !args.explain || args.stats_only {
+        return Vec::new();
+    }
+    let nq = if queries.is_multi() {
+        queries.labels.len()
+    } else {
+        0
+    };
+    let matched: Vec<usize> = (0..lines.len()).filter(|&i| is_match[i]).collect();
+    let texts: Vec<&str> = matched.iter().map(|&i| lines[i].as_str()).collect();
+    let vecs: Vec<&[f32]> = matched
+        .iter()
+        .map(|&i| queries.best_vec(&query_scores[i * nq..(i + 1) * nq]))
+        .collect();
+    let found = explain::explain(&texts, &vecs, |words| {
+        encode_normalized(model, words, batch_size)
+    });
+    let mut out: Vec<Vec<Contribution>> = lines.iter().map(|_| Vec::new()).collect();
+    for (i, contributions) in matched.into_iter().zip(found) {
+        out[i] = contributions;
+    }
+    out
+}
 
-        let start = i.saturating_sub(cli.before);
-        let mut end = (i + 1 + cli.after).min(input_lines.len());
-        // Expand window to include subsequent nearby matches while overlapping
-        let mut j = i + 1;
-        while j < input_lines.len() {
-            if is_match[j] {
-                let candidate_start = j.saturating_sub(cli.before);
-                if candidate_start <= end {
-                    // overlap, extend
-                    end = (j + 1 + cli.after).min(input_lines.len());
-                    j += 1;
-                    continue;
-                }
-            }
-            break;
-        }
+fn main() -> Result<()> {
+    let mut cli = Cli::parse();
+    // Code units span many lines; number them so their line range shows
+    cli.matching.line_number |= cli.records.code;
+    // --follow streams the given files instead of stdin
+    cli.stream |= cli.follow;
+    cli.matching.table = cli.records.delimiter();
+
+    match &cli.command {
+        Some(Command::Cache { action, cache }) => return run_cache_command(action, cache),
+        Some(Command::Index(args)) => return run_index(args),
+        Some(Command::Search(args)) => return run_search(args),
+        None => {}
+    }
+    let labels = query::collect(&cli.queries)?;
+    // With -e/-f the positional query slot holds the first path, like grep
+    let (labels, paths) = if labels.is_empty() {
+        let query = cli.query.clone().context("missing query")?;
+        (vec![query], cli.paths.clone())
+    } else {
+        let paths: Vec<PathBuf> = cli
+            .query
+            .iter()
+            .map(PathBuf::from)
+            .chain(cli.paths.clone())
+            .collect();
+        (labels, paths)
+    };
+    ensure!(
+        !cli.stream || cli.follow || paths.is_empty(),
+        "paths can't be used with --stream (use --follow to follow files)"
+    );
+    ensure!(
+        !cli.follow || !paths.is_empty(),
+        "--follow needs files to follow"
+    );
+    ensure!(
+        !cli.stream
+            || cli.matching.top.is_none()
+            || cli.window_lines.is_some()
+            || cli.window_secs.is_some(),
+        "--top with --stream needs --window-lines or --window-secs"
+    );
 
-        // Print block with separators similar to grep
-        for k in start..end {
-            let line = &input_lines[k];
-            if is_match[k] {
-                let score = scores[k];
-                if !cli.hide_scores {
-                    println!("{}\t[{:.3}]", line, score);
-                } else {
-                    println!("{}", line);
-                }
-            } else {
-                println!("{}", line);
-            }
-        }
+    // Load model (normalize embeddings enabled by default config unless overridden)
+    let model = StaticModel::from_pretrained(&cli.model_args.model, None, None, None)
+        .context("failed to load model")?;
 
-        // Print a separator between blocks if not at end
-        if end < input_lines.len() {
-            println!("--");
-        }
+    // Encode queries once
+    let queries = Queries::encode(&model, labels, &cli.queries);
 
-        i = end; // continue after this block
+    if cli.stream {
+        stream::run(&cli, &model, &queries, &paths)?;
+        return Ok(());
     }
 
-    // Summary distribution at end (overall distribution to aid threshold selection)
-    let mut all_scores: Vec<f32> = scores.clone();
-    all_scores.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    // If reading from piped stdin without --stream, print a hint once
+    if input::reads_stdin(&paths) && !io::stdin().is_terminal() {
+        eprintln!(
+            "reading from stdin until EOF. For endless inputs (e.g., tail -f), use --stream to process incrementally"
+        );
+    }
 
-    let q = |p: f32| -> f32 {
-        // Return the p-quantile (0..=1) using nearest-rank on sorted ascending
-        if all_scores.is_empty() {
-            return 0.0;
+    // Read all input lines first to preserve order for context windows
+    let framing = Framing::new(&cli.records)?;
+    let mut corpus = input::gather(&paths, &cli.walk, &framing)?;
+
+    // With --csv/--tsv only the chosen column of each row is embedded; rows print whole.
+    // A source whose header lacks the column is reported and skipped like an unreadable file.
+    let table_columns: Option<Vec<Columns>> = match &framing {
+        Framing::Table(delimiter) => {
+            let mut columns = Vec::with_capacity(corpus.sources.len());
+            let csv_column = cli.records.csv_column.as_deref();
+            corpus.retain_sources(|source| {
+                let Some(header) = source.header.as_deref() else {
+                    // Empty: no rows to embed
+                    columns.push(Columns::new(*delimiter, None, "").expect("no column to find"));
+                    return true;
+                };
+                match Columns::new(*delimiter, csv_column, header) {
+                    Ok(found) => {
+                        columns.push(found);
+                        true
+                    }
+                    Err(err) => {
+                        let path = source.path.as_deref().unwrap_or("(standard input)");
+                        eprintln!("vecgrep: {}: {}", path, err);
+                        false
+                    }
+                }
+            });
+            Some(columns)
         }
-        let n = all_scores.len();
-        let idx = ((n as f32 - 1.0) * p).round() as usize;
-        all_scores[idx]
+        _ => None,
+    };
+    let input_lines = &corpus.lines;
+    let table_texts: Option<Vec<String>> = table_columns.map(|columns| {
+        corpus
+            .sources
+            .iter()
+            .zip(&columns)
+            .flat_map(|(source, columns)| {
+                input_lines[source.range.clone()]
+                    .iter()
+                    .map(|row| columns.embed_text(row))
+            })
+            .collect()
+    });
+    let embed_lines = table_texts.as_deref().unwrap_or(input_lines);
+
+    // Only lines passing --prefilter/--exclude are embedded and scored; the rest remain context
+    let filter = LineFilter::new(&cli.filter)?;
+    let kept: Option<Vec<usize>> = filter.is_active().then(|| {
+        (0..input_lines.len())
+            .into_par_iter()
+            .filter(|&i| filter.keep(&input_lines[i]))
+            .collect()
+    });
+    let (lines, ranges): (Cow<[String]>, Vec<Range<usize>>) = match &kept {
+        None => (
+            Cow::Borrowed(embed_lines),
+            corpus.sources.iter().map(|s| s.range.clone()).collect(),
+        ),
+        Some(kept) => (
+            Cow::Owned(kept.iter().map(|&i| embed_lines[i].clone()).collect()),
+            corpus
+                .sources
+                .iter()
+                .map(|s| {
+                    kept.partition_point(|&i| i < s.range.start)
+                        ..kept.partition_point(|&i| i < s.range.end)
+                })
+                .collect(),
+        ),
     };
 
-    let min_all = *all_scores.first().unwrap_or(&0.0);
-    let max_all = *all_scores.last().unwrap_or(&0.0);
-    let p50_all = q(0.50);
-    let p90_all = q(0.90);
-    let p95_all = q(0.95);
-    let p99_all = q(0.99);
-    let p999_all = q(0.999);
+    // With --json-field only the chosen fields are embedded (and BM25-scored)
+    let extractor = Extractor::new(&cli.fields);
+    let lines = match &extractor {
+        None => lines,
+        Some(extractor) => {
+            let texts = extractor.embed_texts(&lines);
+            extractor.report(texts.len());
+            Cow::Owned(texts)
+        }
+    };
 
-    println!("--");
-    eprintln!("{}", selection_summary);
-    eprintln!(
-        "overall distribution (all lines): min {:.3}  p50 {:.3}  p90 {:.3}  p95 {:.3}  p99 {:.3}  p99.9 {:.3}  max {:.3}",
-        min_all, p50_all, p90_all, p95_all, p99_all, p999_all, max_all
+    // Encode all lines in batches, reusing cached embeddings where content is unchanged
+    let encode = |lines: &[String]| encode_normalized(&model, lines, cli.model_args.batch_size);
+    let cache = if cli.no_cache {
+        None
+    } else {
+        match cache::default_dir().and_then(|dir| {
+            cache::EmbeddingCache::open(dir, &cli.model_args.model, cli.cache.cache_size)
+        }) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                eprintln!("vecgrep: embedding cache disabled: {:#}", err);
+                None
+            }
+        }
+    };
+    // With --chunk-size long lines are embedded and scored as windows of words
+    let windows = Chunker::new(&cli.chunk)?.map(|chunker| chunker.split(&lines));
+    let (texts, ranges): (&[String], Vec<Range<usize>>) = match &windows {
+        None => (&lines, ranges),
+        Some(windows) => (&windows.texts, windows.ranges(&ranges)),
+    };
+    // --normalize/--strip-prefix rewrite what gets embedded, window by window
+    let cleaned = Preprocessor::new(&cli.preprocess)?.map(|p| p.apply_all(texts));
+    let texts: &[String] = cleaned.as_deref().unwrap_or(texts);
+    let norm_embeddings = match &cache {
+        Some(cache) => cache.encode(texts, &ranges, encode),
+        None => encode(texts),
+    };
+
+    // Compute similarity per line (and query) once
+    let lexical = queries.lexical_scores(texts);
+    let (scores, query_scores, excluded) = queries.score_all(&norm_embeddings, &lexical);
+    // Each line keeps its best window
+    let (scores, query_scores, excluded, spans) = match &windows {
+        None => (scores, query_scores, excluded, Vec::new()),
+        Some(windows) => windows.reduce(&scores, &query_scores, &excluded),
+    };
+    let (is_match, selection_summary) = select_matches(&scores, &excluded, &cli.matching);
+    let match_count = is_match.iter().filter(|&&m| m).count();
+    // Stats cover the scored lines only
+    let distribution = Distribution::from_scores(&scores);
+
+    // Back to one entry per input line for printing; skipped lines never match
+    let (scores, query_scores, is_match, spans) = match &kept {
+        None => (scores, query_scores, is_match, spans),
+        Some(kept) => {
+            let n = input_lines.len();
+            let nq = query_scores.len() / kept.len().max(1);
+            let stride = usize::from(!spans.is_empty());
+            (
+                scatter(&scores, kept, n, 1, 0.0),
+                scatter(&query_scores, kept, n, nq, 0.0),
+                scatter(&is_match, kept, n, 1, false),
+                scatter(&spans, kept, n, stride, None),
+            )
+        }
+    };
+    let explanations = explain_matches(
+        &cli.matching,
+        &model,
+        cli.model_args.batch_size,
+        &queries,
+        input_lines,
+        &is_match,
+        &query_scores,
     );
-    eprintln!(
-        "suggested thresholds for top k%% lines: 5%%→{:.3}  1%%→{:.3}  0.1%%→{:.3}  0.01%%→{:.3}",
-        p95_all,
-        p99_all,
-        p999_all,
-        q(0.9999)
+    output::print_matches(
+        &cli.matching,
+        &corpus,
+        &Results {
+            is_match: &is_match,
+            scores: &scores,
+            labels: queries.multi_labels(),
+            query_scores: &query_scores,
+            explanations: &explanations,
+            windows: &spans,
+        },
     );
+    output::print_summary(
+        &cli.matching,
+        Some(&distribution),
+        input_lines.len() - lines.len(),
+        match_count,
+        &selection_summary,
+    )?;
 
     Ok(())
 }
 
-fn run_stream(cli: &Cli, model: &StaticModel, query_vec: &[f32]) -> Result<()> {
-    let threshold = cli.threshold;
-    let mut before_buf: VecDeque<String> = VecDeque::with_capacity(cli.before.max(1));
-    let mut after_remaining: usize = 0;
-    let mut printed_any: bool = false;
-    let mut printed_prev_line: bool = false;
+/// Spread per-kept-line values (`stride` per line) back over all `n` lines
+fn scatter<T: Clone>(values: &[T], kept: &[usize], n: usize, stride: usize, fill: T) -> Vec<T> {
+    let mut out = vec![fill; n * stride];
+    for (k, &i) in kept.iter().enumerate() {
+        out[i * stride..(i + 1) * stride].clone_from_slice(&values[k * stride..(k + 1) * stride]);
+    }
+    out
+}
 
-    let stdin = io::stdin();
-    let lines = stdin.lock().lines();
+fn run_cache_command(action: &CacheAction, args: &CacheArgs) -> Result<()> {
+    let dir = cache::default_dir()?;
+    match action {
+        CacheAction::Stats => {
+            let stats = cache::stats(&dir)?;
+            println!("location: {}", dir.display());
+            println!("entries: {}", stats.entries);
+            println!(
+                "size: {:.1} MiB (limit {:.1} MiB)",
+                stats.bytes as f64 / (1u64 << 20) as f64,
+                args.cache_size as f64 / (1u64 << 20) as f64
+            );
+        }
+        CacheAction::Clear => {
+            cache::clear(&dir)?;
+            eprintln!("cleared {}", dir.display());
+        }
+    }
+    Ok(())
+}
 
-    for line_res in lines {
-        let line = line_res.context("failed reading stdin line")?;
+fn run_index(args: &IndexArgs) -> Result<()> {
+    let model = StaticModel::from_pretrained(&args.model_args.model, None, None, None)
+        .context("failed to load model")?;
+    let files = input::files(&args.paths, &args.walk)?;
+    let batch_size = args.model_args.batch_size;
+    // Gather several encoder batches across small files before calling the model
+    let stats = index::build(
+        &args.index,
+        &args.model_args.model,
+        &files,
+        batch_size.saturating_mul(16),
+        args.ann,
+        |lines| encode_normalized(&model, lines, batch_size),
+    )?;
+    eprintln!(
+        "indexed {} files ({} unchanged), {} lines ({} embedded) into {}",
+        stats.files,
+        stats.reused_files,
+        stats.total_lines,
+        stats.embedded_lines,
+        args.index.display()
+    );
+    Ok(())
+}
 
-        // Encode and score current line
-        let mut emb = model.encode(std::slice::from_ref(&line))[0].clone();
-        normalize(&mut emb);
-        let score = cosine_similarity(query_vec, &emb);
-        let is_match = score >= threshold;
+fn run_search(args: &SearchArgs) -> Result<()> {
+    let index = index::Index::open(&args.index)?;
+    // Queries must be embedded with the model the index was built with
+    let model = StaticModel::from_pretrained(&index.model_id, None, None, None)
+        .context("failed to load model")?;
+    // Without a path argument the positional query and -e/-f queries all count
+    let mut labels: Vec<String> = args.query.iter().cloned().collect();
+    labels.extend(query::collect(&args.queries)?);
+    let queries = Queries::encode(&model, labels, &args.queries);
+    // The index holds vectors only; BM25 would need every line's text
+    ensure!(
+        !queries.is_hybrid(),
+        "--lexical-weight isn't supported by search"
+    );
 
-        if is_match {
-            // New block separator if we didn't just print a line
-            if printed_any && !printed_prev_line {
-                println!("--");
-            }
-            // Before-context only when starting a fresh block
-            if !printed_prev_line && cli.before > 0 {
-                for ctx in before_buf.iter() {
-                    println!("{}", ctx);
-                }
-            }
-            if !cli.hide_scores {
-                println!("{}\t[{:.3}]", line, score);
-            } else {
-                println!("{}", line);
+    // --top can use the ANN structure; everything else scores every line
+    let ann = match (args.matching.top, args.exact) {
+        (Some(top), false) => index.ann().transpose()?.map(|ann| (ann, top)),
+        _ => None,
+    };
+    let approx = ann.and_then(|(ann, top)| {
+        // Union of each query's approximate top lines, best score per line
+        let mut hits: Vec<(usize, f32)> = queries
+            .vecs
+            .iter()
+            .flat_map(|q| ann.search(&index, q, top, args.nprobe))
+            .collect();
+        hits.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)));
+        hits.dedup_by_key(|h| h.0);
+        let candidates = hits.len();
+        hits.retain_mut(|(i, score)| {
+            let (row, dropped) = queries.score_line(|q| index.dot(*i, q), &[]);
+            *score = query::best(&row).1;
+            !dropped
+        });
+        // If --not dropped too many candidates, score every line instead
+        if hits.len() < candidates && hits.len() < top {
+            return None;
+        }
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+        hits.truncate(top);
+        Some(hits)
+    });
+    let (scores, is_match, selection_summary, distribution) = match approx {
+        Some(hits) => {
+            let mut scores = vec![0.0f32; index.len()];
+            let mut is_match = vec![false; index.len()];
+            for &(i, score) in &hits {
+                scores[i] = score;
+                is_match[i] = true;
             }
-            printed_any = true;
-            printed_prev_line = true;
-            after_remaining = cli.after;
-        } else if after_remaining > 0 {
-            println!("{}", line);
-            printed_any = true;
-            printed_prev_line = true;
-            after_remaining -= 1;
-        } else {
-            printed_prev_line = false;
+            let min_selected = hits.iter().map(|h| h.1).fold(1.0f32, f32::min);
+            let summary = format!(
+                "selected top {} lines by approximate similarity (nprobe {}, min selected score {:.3}); use --exact to verify",
+                hits.len(),
+                args.nprobe,
+                min_selected
+            );
+            (scores, is_match, summary, false)
+        }
+        None => {
+            let (scores, excluded): (Vec<f32>, Vec<bool>) = (0..index.len())
+                .into_par_iter()
+                .map(|i| {
+                    let (row, dropped) = queries.score_line(|q| index.dot(i, q), &[]);
+                    (query::best(&row).1, dropped)
+                })
+                .unzip();
+            let (is_match, summary) = select_matches(&scores, &excluded, &args.matching);
+            (scores, is_match, summary, true)
         }
+    };
 
-        // Maintain before buffer
-        if cli.before > 0 {
-            if before_buf.len() == cli.before {
-                before_buf.pop_front();
+    // Only files with matches are read back, slicing lines by their stored offsets
+    let mut corpus = Corpus::default();
+    let mut local_match = Vec::new();
+    let mut local_scores = Vec::new();
+    let mut local_query_scores = Vec::new();
+    for entry in &index.files {
+        let range = entry.first_line..entry.first_line + entry.line_count;
+        if !is_match[range.clone()].contains(&true) {
+            continue;
+        }
+        let bytes = match fs::metadata(&entry.path) {
+            Ok(meta) if entry.is_fresh(&meta) => fs::read(&entry.path)?,
+            Ok(_) => {
+                eprintln!(
+                    "vecgrep: {}: changed since indexing, skipped (re-run vecgrep index)",
+                    entry.path
+                );
+                continue;
+            }
+            Err(err) => {
+                eprintln!("vecgrep: {}: {}", entry.path, err);
+                continue;
+            }
+        };
+        let offsets: Vec<u64> = range.clone().map(|i| index.offset(i)).collect();
+        let lines = input::lines_at(&bytes, &offsets);
+        corpus.push_source(Some(entry.path.clone()), lines, offsets);
+        local_match.extend_from_slice(&is_match[range.clone()]);
+        if queries.is_multi() {
+            for i in range.clone() {
+                local_query_scores.extend(queries.score_line(|q| index.dot(i, q), &[]).0);
             }
-            before_buf.push_back(line);
         }
+        local_scores.extend_from_slice(&scores[range]);
     }
 
+    // Search has no --batch-size; the matched lines' words are few anyway
+    let explanations = explain_matches(
+        &args.matching,
+        &model,
+        1024,
+        &queries,
+        &corpus.lines,
+        &local_match,
+        &local_query_scores,
+    );
+    output::print_matches(
+        &args.matching,
+        &corpus,
+        &Results {
+            is_match: &local_match,
+            scores: &local_scores,
+            labels: queries.multi_labels(),
+            query_scores: &local_query_scores,
+            explanations: &explanations,
+            windows: &[],
+        },
+    );
+    // Approximate search never sees most scores, so there is no distribution to report
+    let match_count = is_match.iter().filter(|&&m| m).count();
+    output::print_summary(
+        &args.matching,
+        distribution
+            .then(|| Distribution::from_scores(&scores))
+            .as_ref(),
+        0,
+        match_count,
+        &selection_summary,
+    )?;
     Ok(())
 }
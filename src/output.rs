@@ -0,0 +1,558 @@
+use crate::cli::{MatchArgs, StatsFormat};
+use crate::color::{paint, Palette};
+use crate::explain::Contribution;
+use crate::input::{Corpus, Source};
+use crate::stats::{round_score, Distribution};
+use anyhow::Result;
+use serde_json::json;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// One line to print, shared by the batch and stream printers
+pub struct LineOut<'a> {
+    pub path: Option<&'a str>,
+    pub line_number: usize,
+    /// Byte offset of the line start within its file or stream
+    pub byte_offset: u64,
+    pub text: &'a str,
+    pub score: f32,
+    pub is_match: bool,
+    /// Consecutive lines printed together (a match plus its context) share a block
+    pub block_id: usize,
+    /// Words that drove the match (--explain), best first
+    pub explain: &'a [Contribution],
+    /// Query texts and this line's score for each, when there are several queries
+    pub labels: &'a [String],
+    pub query_scores: &'a [f32],
+    /// Byte span of the best-scoring window with --chunk-size, when the line was split
+    pub window: Option<&'a Range<usize>>,
+}
+
+/// Per-line results of a batch search, indexed like the corpus lines
+pub struct Results<'a> {
+    pub is_match: &'a [bool],
+    /// Best score over all queries
+    pub scores: &'a [f32],
+    /// Query texts when there are several, else empty
+    pub labels: &'a [String],
+    /// Score per query, `labels.len()` per line
+    pub query_scores: &'a [f32],
+    /// Word contributions with --explain, else empty
+    pub explanations: &'a [Vec<Contribution>],
+    /// Best window of each line with --chunk-size, else empty
+    pub windows: &'a [Option<Range<usize>>],
+}
+
+/// Print matches with context per source, merging overlapping windows
+pub fn print_matches(args: &MatchArgs, corpus: &Corpus, results: &Results) {
+    if args.stats_only {
+        return;
+    }
+    let mut block_id = 0usize;
+    for source in &corpus.sources {
+        print_source(args, corpus, source, results, &mut block_id);
+    }
+}
+
+/// Print the matches of one source with their context windows.
+/// Windows never cross into a neighbouring source.
+fn print_source(
+    args: &MatchArgs,
+    corpus: &Corpus,
+    source: &Source,
+    results: &Results,
+    block_id: &mut usize,
+) {
+    let is_match = results.is_match;
+    let nq = results.labels.len();
+    let input_lines = &corpus.lines;
+    let mut header = source.header.as_deref();
+    let mut i = source.range.start;
+    while i < source.range.end {
+        if !is_match[i] {
+            i += 1;
+            continue;
+        }
+
+        let start = i.saturating_sub(args.before).max(source.range.start);
+        let mut end = (i + 1 + args.after).min(source.range.end);
+        // Expand window to include subsequent nearby matches while overlapping
+        let mut j = i + 1;
+        while j < source.range.end {
+            if is_match[j] {
+                let candidate_start = j.saturating_sub(args.before);
+                if candidate_start <= end {
+                    // overlap, extend
+                    end = (j + 1 + args.after).min(source.range.end);
+                    j += 1;
+                    continue;
+                }
+            }
+            break;
+        }
+
+        // A source's header row precedes its first matching row
+        if let Some(header) = header.take() {
+            print_header(args, header);
+        }
+
+        // Print block with separators similar to grep
+        for k in start..end {
+            print_line(
+                args,
+                &LineOut {
+                    path: source.path.as_deref(),
+                    line_number: corpus.line_numbers[k],
+                    byte_offset: corpus.offsets[k],
+                    text: &input_lines[k],
+                    score: results.scores[k],
+                    is_match: is_match[k],
+                    block_id: *block_id,
+                    explain: results.explanations.get(k).map_or(&[], Vec::as_slice),
+                    labels: results.labels,
+                    query_scores: results
+                        .query_scores
+                        .get(k * nq..(k + 1) * nq)
+                        .unwrap_or(&[]),
+                    window: results.windows.get(k).and_then(Option::as_ref),
+                },
+            );
+        }
+        *block_id += 1;
+
+        // Print a separator between blocks if not at end
+        if end < input_lines.len() {
+            print_separator(args);
+        }
+
+        i = end; // continue after this block
+    }
+}
+
+/// Print a match or context line as text or as a JSON object
+pub fn print_line(args: &MatchArgs, line: &LineOut) {
+    write_line(&mut io::stdout().lock(), args, line).expect("failed printing to stdout");
+}
+
+/// `print_line` to any writer
+pub fn write_line(out: &mut dyn Write, args: &MatchArgs, line: &LineOut) -> io::Result<()> {
+    if args.json {
+        let mut obj = json!({
+            "type": if line.is_match { "match" } else { "context" },
+            "line_number": line.line_number,
+            "byte_offset": line.byte_offset,
+            "text": line.text,
+            "score": round_score(line.score),
+            "is_context": !line.is_match,
+            "block_id": line.block_id,
+        });
+        if let Some(path) = line.path {
+            obj["path"] = json!(path);
+        }
+        let extra_lines = line.text.matches('\n').count();
+        if extra_lines > 0 {
+            obj["end_line_number"] = json!(line.line_number + extra_lines);
+        }
+        if line.is_match && !line.labels.is_empty() {
+            let hits: Vec<_> = hits(args, line)
+                .into_iter()
+                .map(|(query, score)| json!({ "query": query, "score": round_score(score) }))
+                .collect();
+            obj["queries"] = json!(hits);
+        }
+        if let (true, Some(window)) = (line.is_match, line.window) {
+            let (start, end) = char_span(line.text, window);
+            obj["window"] = json!({ "start": start, "end": end });
+        }
+        if line.is_match && args.explain {
+            let words: Vec<_> = line
+                .explain
+                .iter()
+                .map(|c| json!({ "word": c.word, "score": round_score(c.score) }))
+                .collect();
+            obj["explain"] = json!(words);
+        }
+        return writeln!(out, "{}", obj);
+    }
+    if let Some(delimiter) = args.table {
+        // Keep --csv/--tsv output a valid table: the row as read, plus its score
+        return if args.hide_scores {
+            writeln!(out, "{}", line.text)
+        } else {
+            writeln!(out, "{}{}{:.3}", line.text, delimiter, line.score)
+        };
+    }
+
+    // Prefix fields like grep -Hnb: ':' after match lines, '-' after context.
+    // Records print one physical line at a time, each with its own line number.
+    let p = palette(args);
+    let sep = paint(&p.separator, if line.is_match { ":" } else { "-" });
+    let column = args.column || args.vimgrep;
+    let mut start = 0;
+    for (n, text) in line.text.split('\n').enumerate() {
+        let mut buf = String::new();
+        let mut field = |sgr: &str, value: &str| {
+            buf.push_str(&paint(sgr, value));
+            buf.push_str(&sep);
+        };
+        if let Some(path) = line.path {
+            field(&p.path, path);
+        }
+        if line.path.is_some() || args.line_number || column {
+            field(&p.line, &(line.line_number + n).to_string());
+        }
+        if column && line.is_match && n == 0 {
+            // Point at the best window if it starts on this line, else at the first
+            // non-blank character since the whole line matches
+            let col = match line.window {
+                Some(w) if w.start < text.len() => w.start + 1,
+                _ => text.len() - text.trim_start().len() + 1,
+            };
+            field(&p.line, &col.to_string());
+        }
+        if args.byte_offset {
+            field(&p.line, &(line.byte_offset + start as u64).to_string());
+        }
+        if !line.is_match {
+            buf.push_str(&paint(&p.context, text));
+        } else {
+            buf.push_str(&highlight(p, text, start, line.explain, line.window));
+            if n == 0 {
+                push_tags(args, p, line, &mut buf);
+            }
+        }
+        writeln!(out, "{}", buf)?;
+        start += text.len() + 1;
+    }
+    Ok(())
+}
+
+/// Score (or per-query tags) and --explain words after the first line of a match
+fn push_tags(args: &MatchArgs, p: &Palette, line: &LineOut, out: &mut String) {
+    if !line.labels.is_empty() {
+        // Tag the line with every query it hit: [query: score]
+        for (query, score) in hits(args, line) {
+            let tag = if args.hide_scores {
+                format!("[{}]", query)
+            } else {
+                format!("[{}: {:.3}]", query, score)
+            };
+            out.push('\t');
+            out.push_str(&paint(p.score(score), &tag));
+        }
+    } else if !args.hide_scores {
+        let score = format!("[{:.3}]", line.score);
+        out.push('\t');
+        out.push_str(&paint(p.score(line.score), &score));
+    }
+    if args.explain {
+        let words: Vec<String> = line
+            .explain
+            .iter()
+            .map(|c| format!("{} {:.3}", c.word, c.score))
+            .collect();
+        out.push_str(&format!("\t({})", words.join(", ")));
+    }
+    if let Some(window) = line.window {
+        let (start, end) = char_span(line.text, window);
+        out.push_str(&format!("\t[chars {}-{}]", start, end));
+    }
+}
+
+/// Character (not byte) offsets of a span of `text`, end exclusive
+fn char_span(text: &str, span: &Range<usize>) -> (usize, usize) {
+    let start = text[..span.start].chars().count();
+    (start, start + text[span.clone()].chars().count())
+}
+
+/// Queries a match line hit: those at or above the threshold, or the best one with --top
+fn hits<'a>(args: &MatchArgs, line: &LineOut<'a>) -> Vec<(&'a str, f32)> {
+    let all = line
+        .labels
+        .iter()
+        .map(String::as_str)
+        .zip(line.query_scores.iter().copied());
+    let mut hits: Vec<_> = if args.top.is_some() {
+        Vec::new()
+    } else {
+        all.clone().filter(|&(_, s)| s >= args.threshold).collect()
+    };
+    if hits.is_empty() {
+        hits.extend(all.max_by(|a, b| a.1.total_cmp(&b.1)));
+    }
+    hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+    hits
+}
+
+/// Match text with the explaining words picked out in the `explain` color and, with
+/// --chunk-size, the text outside the best window in the `context` color.
+/// `text` starts at byte `base` of the whole (record) text the spans refer to.
+fn highlight(
+    p: &Palette,
+    text: &str,
+    base: usize,
+    explain: &[Contribution],
+    window: Option<&Range<usize>>,
+) -> String {
+    let local = |s: &Range<usize>| {
+        s.start.clamp(base, base + text.len()) - base..s.end.clamp(base, base + text.len()) - base
+    };
+    let mut spans: Vec<_> = if p.explain.is_empty() {
+        Vec::new()
+    } else {
+        explain
+            .iter()
+            .flat_map(|c| c.spans.iter())
+            .filter(|s| s.start >= base && s.end <= base + text.len())
+            .map(local)
+            .collect()
+    };
+    let window = window.map_or(0..text.len(), local);
+    if spans.is_empty() && window == (0..text.len()) {
+        return paint(&p.matched, text);
+    }
+    spans.sort_by_key(|s| s.start);
+
+    // Cut the text wherever the color changes, then paint each piece
+    let mut cuts = vec![0, text.len(), window.start, window.end];
+    cuts.extend(spans.iter().flat_map(|s| [s.start, s.end]));
+    cuts.sort_unstable();
+    cuts.dedup();
+    let color = |at: usize| {
+        if spans.iter().any(|s| s.contains(&at)) {
+            &p.explain
+        } else if window.contains(&at) {
+            &p.matched
+        } else {
+            &p.context
+        }
+    };
+    let mut out = String::new();
+    for piece in cuts.windows(2) {
+        out.push_str(&paint(color(piece[0]), &text[piece[0]..piece[1]]));
+    }
+    out
+}
+
+/// Colors are resolved once per run from --color, NO_COLOR and VECGREP_COLORS
+fn palette(args: &MatchArgs) -> &'static Palette {
+    static PALETTE: OnceLock<Palette> = OnceLock::new();
+    PALETTE.get_or_init(|| Palette::resolve(args.color))
+}
+
+/// Header row of --csv/--tsv output, with the appended score column
+pub fn print_header(args: &MatchArgs, header: &str) {
+    match args.table {
+        Some(delimiter) if !args.json && !args.stats_only => {
+            if args.hide_scores {
+                println!("{}", header);
+            } else {
+                println!("{}{}score", header, delimiter);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Block separator, omitted in JSON output where `block_id` groups lines instead,
+/// and in --vimgrep and --csv/--tsv output where every line stands alone
+pub fn print_separator(args: &MatchArgs) {
+    write_separator(&mut io::stdout().lock(), args).expect("failed printing to stdout");
+}
+
+/// `print_separator` to any writer
+pub fn write_separator(out: &mut dyn Write, args: &MatchArgs) -> io::Result<()> {
+    if !args.json && !args.vimgrep && args.table.is_none() {
+        let p = palette(args);
+        writeln!(out, "{}", paint(&p.separator, "--"))?;
+    }
+    Ok(())
+}
+
+/// A --window-lines/--window-secs window of a --stream --top search
+pub struct WindowOut {
+    /// 0-based, the `block_id` of the window's lines
+    pub index: usize,
+    /// Lines (or records) read in the window
+    pub read: usize,
+    /// Lines printed as its best
+    pub selected: usize,
+    pub first_line: usize,
+    pub last_line: usize,
+    /// Seconds since the stream started, with --window-secs
+    pub secs: Option<Range<u64>>,
+}
+
+/// Marker printed ahead of each window's top lines; left out of --vimgrep and
+/// --csv/--tsv output like block separators
+pub fn print_window(args: &MatchArgs, window: &WindowOut) {
+    if args.json {
+        let mut obj = json!({
+            "type": "window",
+            "block_id": window.index,
+            "first_line": window.first_line,
+            "last_line": window.last_line,
+            "lines": window.read,
+            "matches": window.selected,
+        });
+        if let Some(secs) = &window.secs {
+            obj["start_secs"] = json!(secs.start);
+            obj["end_secs"] = json!(secs.end);
+        }
+        println!("{}", obj);
+    } else if !args.vimgrep && args.table.is_none() {
+        let p = palette(args);
+        let secs = match &window.secs {
+            Some(secs) => format!(" ({}s-{}s)", secs.start, secs.end),
+            None => String::new(),
+        };
+        let marker = format!(
+            "== window {}{}: lines {}-{}, top {} of {} ==",
+            window.index + 1,
+            secs,
+            window.first_line,
+            window.last_line,
+            window.selected,
+            window.read
+        );
+        println!("{}", paint(&p.separator, &marker));
+    }
+}
+
+/// Summary distribution at end (overall distribution to aid threshold selection).
+/// Without a distribution only the selection summary is reported. `skipped` lines were
+/// left out by --prefilter/--exclude and are not part of the distribution.
+/// The report follows the matches on stderr, or goes to stdout on its own with --stats-only.
+pub fn print_summary(
+    args: &MatchArgs,
+    dist: Option<&Distribution>,
+    skipped: usize,
+    match_count: usize,
+    selection_summary: &str,
+) -> Result<()> {
+    if args.json {
+        println!(
+            "{}",
+            summary_json(dist, skipped, match_count, selection_summary)
+        );
+        return Ok(());
+    }
+
+    let mut out: Box<dyn Write> = if args.stats_only {
+        Box::new(io::stdout().lock())
+    } else {
+        print_separator(args);
+        Box::new(io::stderr().lock())
+    };
+    write_summary(
+        &mut out,
+        args.stats_format,
+        dist,
+        skipped,
+        match_count,
+        selection_summary,
+    )
+}
+
+/// Running --stream statistics: always on stderr, so reports never mix with the
+/// matches, as a JSON object with --json
+pub fn report_stats(
+    args: &MatchArgs,
+    dist: &Distribution,
+    skipped: usize,
+    match_count: usize,
+    selection_summary: &str,
+) -> Result<()> {
+    let format = if args.json {
+        StatsFormat::Json
+    } else {
+        args.stats_format
+    };
+    write_summary(
+        &mut io::stderr().lock(),
+        format,
+        Some(dist),
+        skipped,
+        match_count,
+        selection_summary,
+    )
+}
+
+fn summary_json(
+    dist: Option<&Distribution>,
+    skipped: usize,
+    match_count: usize,
+    selection_summary: &str,
+) -> serde_json::Value {
+    let mut obj = json!({
+        "type": "summary",
+        "selection_summary": selection_summary,
+        "matches": match_count,
+    });
+    if let Some(d) = dist {
+        obj["total_lines"] = json!(d.count + skipped);
+        obj["skipped_lines"] = json!(skipped);
+        if let (Some(obj), serde_json::Value::Object(fields)) = (obj.as_object_mut(), d.to_json()) {
+            obj.extend(fields);
+        }
+    }
+    obj
+}
+
+fn write_summary(
+    out: &mut dyn Write,
+    format: StatsFormat,
+    dist: Option<&Distribution>,
+    skipped: usize,
+    match_count: usize,
+    selection_summary: &str,
+) -> Result<()> {
+    match format {
+        StatsFormat::Text => {
+            writeln!(out, "{}", selection_summary)?;
+            let Some(d) = dist else {
+                return Ok(());
+            };
+            if skipped > 0 {
+                writeln!(
+                    out,
+                    "scored {} of {} lines ({} skipped by --prefilter/--exclude)",
+                    d.count,
+                    d.count + skipped,
+                    skipped
+                )?;
+            }
+            let population = if skipped > 0 {
+                "scored lines"
+            } else {
+                "all lines"
+            };
+            writeln!(
+                out,
+                "overall distribution ({}): min {:.3}  p50 {:.3}  p90 {:.3}  p95 {:.3}  p99 {:.3}  p99.9 {:.3}  max {:.3}",
+                population, d.min, d.p50, d.p90, d.p95, d.p99, d.p999, d.max
+            )?;
+            writeln!(
+                out,
+                "suggested thresholds for top k%% lines: 5%%→{:.3}  1%%→{:.3}  0.1%%→{:.3}  0.01%%→{:.3}",
+                d.p95, d.p99, d.p999, d.p9999
+            )?;
+        }
+        StatsFormat::Json => writeln!(
+            out,
+            "{}",
+            summary_json(dist, skipped, match_count, selection_summary)
+        )?,
+        StatsFormat::Csv => {
+            writeln!(out, "metric,value")?;
+            writeln!(out, "matches,{}", match_count)?;
+            if let Some(d) = dist {
+                writeln!(out, "total_lines,{}", d.count + skipped)?;
+                writeln!(out, "skipped_lines,{}", skipped)?;
+                d.write_csv(out)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use crate::cli::{Normalization, PreprocessArgs};
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use regex::{Captures, Regex, Replacer};
+use std::borrow::Cow;
+
+/// One rewrite of the text to embed
+enum Step {
+    /// Replace every match
+    Mask(Regex, &'static str),
+    /// Replace matches that contain a digit (or start with 0x), so words like
+    /// "deadbeef" survive but commit hashes and addresses don't
+    Hex(Regex),
+    /// Remove a match at the very start
+    Prefix(Regex),
+    Lowercase,
+}
+
+/// Cleans the text that gets embedded so identical events with different IDs, addresses
+/// or times embed alike. Printed lines are left as they are.
+pub struct Preprocessor {
+    steps: Vec<Step>,
+}
+
+impl Preprocessor {
+    /// `None` when neither --normalize nor --strip-prefix is given
+    pub fn new(args: &PreprocessArgs) -> Result<Option<Self>> {
+        let on = |n: Normalization| {
+            args.normalize.contains(&n) || args.normalize.contains(&Normalization::All)
+        };
+        let re = |pattern: &str| Regex::new(pattern).expect("valid regex");
+
+        // Order matters: escapes go before the prefix regex sees the line, and specific
+        // patterns (timestamps, UUIDs, IPs) before the numbers they contain
+        let mut steps = Vec::new();
+        if on(Normalization::Ansi) {
+            steps.push(Step::Mask(
+                re(r"\x1b\[[0-9;?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)"),
+                "",
+            ));
+        }
+        if let Some(prefix) = &args.strip_prefix {
+            let prefix = Regex::new(prefix)
+                .with_context(|| format!("invalid --strip-prefix regex '{}'", prefix))?;
+            steps.push(Step::Prefix(prefix));
+        }
+        if on(Normalization::Timestamp) {
+            steps.push(Step::Mask(
+                re(concat!(
+                    r"\d{4}-\d{2}-\d{2}(?:[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d+)?(?:Z|[+-]\d{2}:?\d{2})?)?",
+                    r"|\b(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)\s+\d{1,2}\s+\d{2}:\d{2}:\d{2}",
+                    r"|\b\d{2}:\d{2}:\d{2}(?:[.,]\d+)?"
+                )),
+                "<time>",
+            ));
+        }
+        if on(Normalization::Uuid) {
+            steps.push(Step::Mask(
+                re(r"\b[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}\b"),
+                "<uuid>",
+            ));
+        }
+        if on(Normalization::Ip) {
+            steps.push(Step::Mask(
+                re(concat!(
+                    r"\b\d{1,3}(?:\.\d{1,3}){3}(?::\d+)?\b",
+                    r"|\b(?:[0-9a-fA-F]{1,4}:){7}[0-9a-fA-F]{1,4}\b",
+                    r"|\b[0-9a-fA-F]{1,4}(?::[0-9a-fA-F]{1,4})*::[0-9a-fA-F]{1,4}(?::[0-9a-fA-F]{1,4})*\b"
+                )),
+                "<ip>",
+            ));
+        }
+        if on(Normalization::Hex) {
+            steps.push(Step::Hex(re(r"\b(?:0[xX][0-9a-fA-F]+|[0-9a-fA-F]{8,})\b")));
+        }
+        if on(Normalization::Number) {
+            steps.push(Step::Mask(re(r"\d+(?:\.\d+)?"), "<num>"));
+        }
+        if on(Normalization::Lowercase) {
+            steps.push(Step::Lowercase);
+        }
+        Ok((!steps.is_empty()).then_some(Self { steps }))
+    }
+
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for step in &self.steps {
+            text = match step {
+                Step::Mask(re, with) => replace(text, re, *with),
+                Step::Hex(re) => replace(text, re, |caps: &Captures| {
+                    let found = &caps[0];
+                    if found.starts_with("0x")
+                        || found.starts_with("0X")
+                        || found.bytes().any(|b| b.is_ascii_digit())
+                    {
+                        "<hex>".to_string()
+                    } else {
+                        found.to_string()
+                    }
+                }),
+                Step::Prefix(re) => match re.find(&text) {
+                    Some(m) if m.start() == 0 && !m.is_empty() => {
+                        Cow::Owned(text[m.end()..].to_string())
+                    }
+                    _ => text,
+                },
+                Step::Lowercase if text.chars().any(char::is_uppercase) => {
+                    Cow::Owned(text.to_lowercase())
+                }
+                Step::Lowercase => text,
+            };
+        }
+        text
+    }
+
+    pub fn apply_all(&self, texts: &[String]) -> Vec<String> {
+        texts
+            .par_iter()
+            .map(|text| self.apply(text).into_owned())
+            .collect()
+    }
+}
+
+/// `Regex::replace_all` that keeps `text` as is when nothing matched
+fn replace<'a>(text: Cow<'a, str>, re: &Regex, with: impl Replacer) -> Cow<'a, str> {
+    let replaced = match re.replace_all(&text, with) {
+        Cow::Owned(replaced) => Some(replaced),
+        Cow::Borrowed(_) => None,
+    };
+    match replaced {
+        Some(replaced) => Cow::Owned(replaced),
+        None => text,
+    }
+}
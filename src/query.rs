@@ -0,0 +1,182 @@
+use crate::cli::QueryArgs;
+use crate::lexical::{self, Bm25};
+use crate::{cosine_similarity, normalize};
+use anyhow::{ensure, Context, Result};
+use model2vec_rs::model::StaticModel;
+use rayon::prelude::*;
+use std::fs;
+
+/// The queries of a run, each embedded once; lines are scored against all of them
+pub struct Queries {
+    /// Query texts, used as labels on printed matches
+    pub labels: Vec<String>,
+    pub vecs: Vec<Vec<f32>>,
+    /// --not queries
+    negatives: Vec<Vec<f32>>,
+    not_threshold: f32,
+    not_weight: Option<f32>,
+    /// Terms of each query for hybrid scoring, and the BM25 share of the score
+    pub terms: Vec<Vec<String>>,
+    pub lexical_weight: f32,
+}
+
+impl Queries {
+    pub fn encode(model: &StaticModel, labels: Vec<String>, args: &QueryArgs) -> Self {
+        let encode = |texts: &[String]| -> Vec<Vec<f32>> {
+            if texts.is_empty() {
+                return Vec::new();
+            }
+            model
+                .encode(texts)
+                .into_iter()
+                .map(|mut v| {
+                    normalize(&mut v);
+                    v
+                })
+                .collect()
+        };
+        Self {
+            vecs: encode(&labels),
+            terms: labels.iter().map(|l| lexical::terms(l)).collect(),
+            labels,
+            negatives: encode(&args.not),
+            not_threshold: args.not_threshold,
+            not_weight: args.not_weight,
+            lexical_weight: args.lexical_weight,
+        }
+    }
+
+    pub fn is_multi(&self) -> bool {
+        self.vecs.len() > 1
+    }
+
+    /// Labels to print per match: the query texts when there are several, else none
+    pub fn multi_labels(&self) -> &[String] {
+        if self.is_multi() {
+            &self.labels
+        } else {
+            &[]
+        }
+    }
+
+    /// Score against every query (`score` computes one query's similarity)
+    fn row(&self, score: impl Fn(&[f32]) -> f32) -> Vec<f32> {
+        self.vecs.iter().map(|q| score(q)).collect()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.lexical_weight > 0.0
+    }
+
+    /// BM25 scores over `lines` (`labels.len()` per line), empty unless hybrid
+    pub fn lexical_scores(&self, lines: &[String]) -> Vec<f32> {
+        if !self.is_hybrid() {
+            return Vec::new();
+        }
+        let mut bm25 = Bm25::default();
+        for line in lines {
+            bm25.add(line);
+        }
+        lines
+            .par_iter()
+            .flat_map_iter(|line| bm25.score(&self.terms, line))
+            .collect()
+    }
+
+    /// Scores of one line against every query, and whether a --not query drops it.
+    /// `lexical` holds the line's BM25 scores when hybrid (else empty).
+    /// With --not-weight the closest --not query is subtracted instead.
+    pub fn score_line(&self, score: impl Fn(&[f32]) -> f32, lexical: &[f32]) -> (Vec<f32>, bool) {
+        let mut row = self.row(&score);
+        lexical::blend(&mut row, lexical, self.lexical_weight);
+        if self.negatives.is_empty() {
+            return (row, false);
+        }
+        let negative = self
+            .negatives
+            .iter()
+            .map(|q| score(q))
+            .fold(f32::NEG_INFINITY, f32::max);
+        match self.not_weight {
+            Some(weight) => {
+                for s in &mut row {
+                    *s -= weight * negative;
+                }
+                (row, false)
+            }
+            None => (row, negative >= self.not_threshold),
+        }
+    }
+
+    /// Best score per line, the per-query scores (`labels.len()` per line) when there
+    /// is more than one query, and the lines dropped by --not (empty without --not).
+    /// `lexical` comes from `lexical_scores`.
+    pub fn score_all(
+        &self,
+        embeddings: &[Vec<f32>],
+        lexical: &[f32],
+    ) -> (Vec<f32>, Vec<f32>, Vec<bool>) {
+        if !self.is_multi() && self.negatives.is_empty() && !self.is_hybrid() {
+            let scores = embeddings
+                .par_iter()
+                .map(|v| cosine_similarity(&self.vecs[0], v))
+                .collect();
+            return (scores, Vec::new(), Vec::new());
+        }
+        let nq = self.vecs.len();
+        let lines: Vec<(Vec<f32>, bool)> = embeddings
+            .par_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let lexical = lexical.get(i * nq..(i + 1) * nq).unwrap_or(&[]);
+                self.score_line(|q| cosine_similarity(q, v), lexical)
+            })
+            .collect();
+        let scores = lines.iter().map(|(row, _)| best(row).1).collect();
+        let excluded = if self.negatives.is_empty() {
+            Vec::new()
+        } else {
+            lines.iter().map(|&(_, dropped)| dropped).collect()
+        };
+        let query_scores = if self.is_multi() {
+            lines.into_iter().flat_map(|(row, _)| row).collect()
+        } else {
+            Vec::new()
+        };
+        (scores, query_scores, excluded)
+    }
+
+    /// Vector of the best-scoring query in `row` (the only query when `row` is empty)
+    pub fn best_vec(&self, row: &[f32]) -> &[f32] {
+        &self.vecs[if row.is_empty() { 0 } else { best(row).0 }]
+    }
+}
+
+/// Index and score of the highest score in a non-empty row
+pub fn best(row: &[f32]) -> (usize, f32) {
+    row.iter()
+        .copied()
+        .enumerate()
+        .fold(
+            (0, f32::NEG_INFINITY),
+            |acc, (i, s)| if s > acc.1 { (i, s) } else { acc },
+        )
+}
+
+/// Queries given with -e, then those read from -f (one per line; blank lines and
+/// lines starting with '#' are skipped). Empty when neither flag is used.
+pub fn collect(args: &QueryArgs) -> Result<Vec<String>> {
+    let mut queries = args.queries.clone();
+    if let Some(path) = &args.query_file {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read query file {}", path.display()))?;
+        queries.extend(
+            text.lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(str::to_string),
+        );
+        ensure!(!queries.is_empty(), "no queries in {}", path.display());
+    }
+    Ok(queries)
+}
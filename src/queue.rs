@@ -0,0 +1,248 @@
+use crate::cli::Overflow;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+/// What `Queue::pop` found
+pub enum Pop<T> {
+    Item(T),
+    /// The deadline passed with the queue empty
+    Timeout,
+    /// The producer is done and everything was taken
+    Closed,
+}
+
+/// Queued items the overflow policy may discard: lines may go, errors must be seen
+pub trait Droppable {
+    fn droppable(&self) -> bool;
+}
+
+impl<T, E> Droppable for Result<T, E> {
+    fn droppable(&self) -> bool {
+        self.is_ok()
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+    /// Items discarded by the overflow policy
+    dropped: usize,
+}
+
+/// Bounded queue between the stream reader and the scorer. When it is full, the
+/// overflow policy either blocks the reader (backpressure on the pipe) or drops lines,
+/// so a flood of input never grows memory without bound.
+pub struct Queue<T> {
+    state: Mutex<State<T>>,
+    changed: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl<T: Droppable> Queue<T> {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+                dropped: 0,
+            }),
+            changed: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+
+    pub fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        if state.items.len() >= self.capacity {
+            let before = state.items.len();
+            match self.overflow {
+                Overflow::Block => {
+                    state = self
+                        .changed
+                        .wait_while(state, |s| s.items.len() >= self.capacity)
+                        .unwrap();
+                }
+                Overflow::DropOldest => {
+                    if let Some(oldest) = state.items.iter().position(T::droppable) {
+                        state.items.remove(oldest);
+                    }
+                }
+                Overflow::Sample => {
+                    // Thin the backlog evenly: keep every other queued line, dropping the
+                    // oldest of each pair so even a queue of one makes room
+                    let mut keep = true;
+                    state.items.retain(|item| {
+                        if !item.droppable() {
+                            return true;
+                        }
+                        keep = !keep;
+                        keep
+                    });
+                }
+            }
+            // Blocking only waited for room; the other policies made it by dropping
+            let dropped = match self.overflow {
+                Overflow::Block => 0,
+                _ => before - state.items.len(),
+            };
+            if dropped > 0 {
+                if state.dropped == 0 {
+                    eprintln!(
+                        "vecgrep: input arrives faster than it can be scored; dropping lines (--overflow {})",
+                        match self.overflow {
+                            Overflow::Sample => "sample",
+                            _ => "drop-oldest",
+                        }
+                    );
+                }
+                state.dropped += dropped;
+            }
+        }
+        state.items.push_back(item);
+        self.changed.notify_all();
+    }
+
+    /// No more items will be pushed
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    /// Take the oldest item, waiting for one until `deadline` (or indefinitely)
+    pub fn pop(&self, deadline: Option<Instant>) -> Pop<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.changed.notify_all();
+                return Pop::Item(item);
+            }
+            if state.closed {
+                return Pop::Closed;
+            }
+            state = match deadline {
+                None => self.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                        return Pop::Timeout;
+                    };
+                    self.changed.wait_timeout(state, timeout).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Items discarded so far by the overflow policy
+    pub fn dropped(&self) -> usize {
+        self.state.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    type Item = Result<u32, &'static str>;
+
+    fn filled(
+        capacity: usize,
+        overflow: Overflow,
+        items: impl IntoIterator<Item = Item>,
+    ) -> Queue<Item> {
+        let queue = Queue::new(capacity, overflow);
+        for item in items {
+            queue.push(item);
+        }
+        queue
+    }
+
+    /// Everything left, once the producer is done
+    fn drain(queue: &Queue<Item>) -> Vec<Item> {
+        queue.close();
+        let mut items = Vec::new();
+        while let Pop::Item(item) = queue.pop(None) {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest() {
+        let queue = filled(3, Overflow::DropOldest, (1..=5).map(Ok));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), [Ok(3), Ok(4), Ok(5)]);
+    }
+
+    #[test]
+    fn sample_thins_the_backlog() {
+        let queue = filled(4, Overflow::Sample, (1..=6).map(Ok));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), [Ok(2), Ok(4), Ok(5), Ok(6)]);
+
+        let queue = filled(1, Overflow::Sample, (1..=3).map(Ok));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), [Ok(3)]);
+    }
+
+    #[test]
+    fn errors_are_never_dropped() {
+        let items = [Err("read failed"), Ok(1), Ok(2), Ok(3)];
+        let queue = filled(3, Overflow::DropOldest, items);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&queue), [Err("read failed"), Ok(2), Ok(3)]);
+
+        let items = [Ok(1), Err("read failed"), Ok(2), Ok(3), Ok(4)];
+        let queue = filled(4, Overflow::Sample, items);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&queue), [Err("read failed"), Ok(2), Ok(4)]);
+
+        // Nothing to drop: the queue goes over capacity rather than lose an error
+        let queue = filled(1, Overflow::DropOldest, [Err("first"), Err("second")]);
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(drain(&queue), [Err("first"), Err("second")]);
+    }
+
+    #[test]
+    fn block_waits_for_room_and_drops_nothing() {
+        let queue: Queue<Item> = Queue::new(2, Overflow::Block);
+        let (about_to_push, signals) = mpsc::channel();
+        thread::scope(|s| {
+            let queue = &queue;
+            s.spawn(move || {
+                for item in 1..=5 {
+                    about_to_push.send(item).unwrap();
+                    queue.push(Ok(item));
+                }
+                queue.close();
+            });
+            let mut items = Vec::new();
+            for next in 3..=5 {
+                // Everything before `next` was pushed, and `next` can't be until one is taken
+                while signals.recv().unwrap() != next {}
+                assert_eq!(queue.state.lock().unwrap().items.len(), 2);
+                if let Pop::Item(item) = queue.pop(None) {
+                    items.push(item);
+                }
+            }
+            while let Pop::Item(item) = queue.pop(None) {
+                items.push(item);
+            }
+            assert_eq!(items, [Ok(1), Ok(2), Ok(3), Ok(4), Ok(5)]);
+        });
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn pop_times_out_until_closed() {
+        let queue = filled(2, Overflow::Block, [Ok(1)]);
+        let now = Some(Instant::now());
+        assert!(matches!(queue.pop(now), Pop::Item(Ok(1))));
+        assert!(matches!(queue.pop(now), Pop::Timeout));
+        queue.close();
+        assert!(matches!(queue.pop(None), Pop::Closed));
+    }
+}
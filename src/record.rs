@@ -0,0 +1,312 @@
+use crate::cli::RecordArgs;
+use crate::input::line_text;
+use crate::table;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::io::BufRead;
+
+/// How input is split into the units that get embedded, scored and printed
+pub enum Framing {
+    Lines,
+    /// A line matching the regex starts a new record (e.g. a log entry with its stack trace)
+    Start(Regex),
+    /// Blank-line separated blocks
+    Paragraph,
+    /// NUL-separated records
+    Null,
+    /// Functions, methods and classes, split per file by `code::split`
+    Code,
+    /// CSV/TSV rows with the given delimiter; the first row of each input is its header
+    Table(char),
+}
+
+impl Framing {
+    pub fn new(args: &RecordArgs) -> Result<Self> {
+        if let Some(re) = &args.record_start {
+            let re =
+                Regex::new(re).with_context(|| format!("invalid --record-start regex '{}'", re))?;
+            return Ok(Framing::Start(re));
+        }
+        if let Some(delimiter) = args.delimiter() {
+            return Ok(Framing::Table(delimiter));
+        }
+        Ok(if args.code {
+            Framing::Code
+        } else if args.paragraph {
+            Framing::Paragraph
+        } else if args.null_data {
+            Framing::Null
+        } else {
+            Framing::Lines
+        })
+    }
+
+    /// Whether a record is only known to be complete once the next one starts (or a
+    /// blank line ends a paragraph), so `Assembler` holds it back until then
+    pub fn holds_back(&self) -> bool {
+        matches!(self, Framing::Start(_) | Framing::Paragraph)
+    }
+}
+
+/// One record: its lines joined with '\n', where it starts and its first line number
+pub struct Unit {
+    pub text: String,
+    pub offset: u64,
+    pub line_number: usize,
+}
+
+/// Splits a byte stream into records. With --record-start a record is only complete
+/// once the next one starts (or the input ends), so it is held back until then.
+pub struct RecordReader<'a, R> {
+    reader: R,
+    framing: &'a Framing,
+    raw: Vec<u8>,
+    next_offset: u64,
+    next_line: usize,
+    assembler: Assembler<'a>,
+}
+
+impl<'a, R: BufRead> RecordReader<'a, R> {
+    pub fn new(reader: R, framing: &'a Framing) -> Self {
+        Self {
+            reader,
+            framing,
+            raw: Vec::new(),
+            next_offset: 0,
+            next_line: 1,
+            assembler: Assembler::new(framing),
+        }
+    }
+
+    /// Number records from this byte offset and line instead of the start of the input
+    pub fn starting_at(mut self, offset: u64, line: usize) -> Self {
+        self.next_offset = offset;
+        self.next_line = line;
+        self
+    }
+
+    pub fn next_record(&mut self) -> Result<Option<Unit>> {
+        match self.framing {
+            Framing::Lines => self.read_line(),
+            Framing::Null => self.read_null(),
+            Framing::Paragraph | Framing::Start(_) => self.read_assembled(),
+            Framing::Table(_) => self.read_row(),
+            // Code is split a whole file at a time, never streamed
+            Framing::Code => unreachable!("--code can't be used with --stream"),
+        }
+    }
+
+    /// Next physical line without its terminator
+    fn read_line(&mut self) -> Result<Option<Unit>> {
+        self.raw.clear();
+        let read = self
+            .reader
+            .read_until(b'\n', &mut self.raw)
+            .context("failed reading input")?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let unit = Unit {
+            text: line_text(self.raw.strip_suffix(b"\n").unwrap_or(&self.raw)),
+            offset: self.next_offset,
+            line_number: self.next_line,
+        };
+        self.next_offset += read as u64;
+        self.next_line += 1;
+        Ok(Some(unit))
+    }
+
+    fn read_null(&mut self) -> Result<Option<Unit>> {
+        self.raw.clear();
+        let read = self
+            .reader
+            .read_until(0, &mut self.raw)
+            .context("failed reading input")?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let body = self.raw.strip_suffix(&[0]).unwrap_or(&self.raw);
+        let body = body.strip_suffix(b"\n").unwrap_or(body);
+        let unit = Unit {
+            text: String::from_utf8_lossy(body).replace("\r\n", "\n"),
+            offset: self.next_offset,
+            line_number: self.next_line,
+        };
+        self.next_offset += read as u64;
+        self.next_line += self.raw.iter().filter(|&&b| b == b'\n').count();
+        Ok(Some(unit))
+    }
+
+    /// A CSV/TSV row. A quoted CSV field may span lines, so lines are joined while a quote
+    /// is open; TSV has no quoting of line breaks, so each line is a row.
+    fn read_row(&mut self) -> Result<Option<Unit>> {
+        let Framing::Table(delimiter) = *self.framing else {
+            unreachable!("read_row needs a table framing");
+        };
+        let Some(mut row) = self.read_line()? else {
+            return Ok(None);
+        };
+        while delimiter == ',' && table::in_quotes(&row.text, delimiter) {
+            match self.read_line()? {
+                Some(line) => {
+                    row.text.push('\n');
+                    row.text.push_str(&line.text);
+                }
+                None => break,
+            }
+        }
+        Ok(Some(row))
+    }
+
+    /// A --record-start record or a paragraph, once its end is known or the input ends
+    fn read_assembled(&mut self) -> Result<Option<Unit>> {
+        while let Some(line) = self.read_line()? {
+            if let Some(record) = self.assembler.push(line) {
+                return Ok(Some(record));
+            }
+        }
+        Ok(self.assembler.flush())
+    }
+}
+
+/// Puts --record-start records and paragraphs together from lines. A record is held
+/// until the line that ends it arrives, or until `flush` when no more are coming (the
+/// input ended, or --stream input went quiet).
+pub struct Assembler<'a> {
+    framing: &'a Framing,
+    record: Option<Unit>,
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(framing: &'a Framing) -> Self {
+        Self {
+            framing,
+            record: None,
+        }
+    }
+
+    /// Add the next line; returns the record it completed, if any. With other framings
+    /// every line is a record of its own.
+    pub fn push(&mut self, line: Unit) -> Option<Unit> {
+        match self.framing {
+            // A matching line starts the next record; the first line always starts one
+            Framing::Start(re) if re.is_match(&line.text) => self.record.replace(line),
+            // A blank line ends a paragraph; blank lines between paragraphs are skipped
+            Framing::Paragraph if line.text.trim().is_empty() => self.record.take(),
+            Framing::Start(_) | Framing::Paragraph => {
+                match &mut self.record {
+                    Some(record) => {
+                        record.text.push('\n');
+                        record.text.push_str(&line.text);
+                    }
+                    None => self.record = Some(line),
+                }
+                None
+            }
+            _ => Some(line),
+        }
+    }
+
+    /// Whether a record is being held back
+    pub fn holding(&self) -> bool {
+        self.record.is_some()
+    }
+
+    /// The record held back, as it is so far
+    pub fn flush(&mut self) -> Option<Unit> {
+        self.record.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(input: &str, delimiter: char) -> Vec<(String, usize)> {
+        let framing = Framing::Table(delimiter);
+        let mut reader = RecordReader::new(input.as_bytes(), &framing);
+        let mut rows = Vec::new();
+        while let Some(unit) = reader.next_record().unwrap() {
+            rows.push((unit.text, unit.line_number));
+        }
+        rows
+    }
+
+    #[test]
+    fn csv_rows_span_lines_inside_quotes() {
+        let input = "id,note\n1,\"two\nlines\"\n2,\"say \"\"hi\"\"\"\n3,27\" monitor\n4,x\n";
+        let rows = rows(input, ',');
+        let texts: Vec<_> = rows
+            .iter()
+            .map(|(text, line)| (text.as_str(), *line))
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                ("id,note", 1),
+                ("1,\"two\nlines\"", 2),
+                ("2,\"say \"\"hi\"\"\"", 4),
+                ("3,27\" monitor", 5),
+                ("4,x", 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn tsv_rows_are_single_lines() {
+        let input = "name\tsize\n27\" monitor\t27\nlaptop\t15\n\"odd\tquote\n";
+        let texts: Vec<_> = rows(input, '\t')
+            .into_iter()
+            .map(|(text, _)| text)
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                "name\tsize",
+                "27\" monitor\t27",
+                "laptop\t15",
+                "\"odd\tquote"
+            ]
+        );
+    }
+
+    fn line(text: &str, line_number: usize) -> Unit {
+        Unit {
+            text: text.to_string(),
+            offset: 0,
+            line_number,
+        }
+    }
+
+    #[test]
+    fn start_records_are_held_until_the_next_starts() {
+        let framing = Framing::Start(Regex::new(r"^\d").unwrap());
+        let mut assembler = Assembler::new(&framing);
+        assert!(assembler.push(line("preamble", 1)).is_none());
+        let record = assembler.push(line("1 first", 2)).unwrap();
+        assert_eq!((record.text.as_str(), record.line_number), ("preamble", 1));
+        assert!(assembler.push(line("  at trace", 3)).is_none());
+        assert!(assembler.holding());
+        let record = assembler.flush().unwrap();
+        assert_eq!(
+            (record.text.as_str(), record.line_number),
+            ("1 first\n  at trace", 2)
+        );
+        assert!(!assembler.holding());
+        assert!(assembler.flush().is_none());
+    }
+
+    #[test]
+    fn paragraphs_skip_blank_runs() {
+        let framing = Framing::Paragraph;
+        let mut reader = RecordReader::new(&b"\none\ntwo\n\n\nthree\n"[..], &framing);
+        let mut records = Vec::new();
+        while let Some(unit) = reader.next_record().unwrap() {
+            records.push((unit.text, unit.line_number));
+        }
+        assert_eq!(
+            records,
+            [("one\ntwo".to_string(), 2), ("three".to_string(), 6)]
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::io::{self, Write};
+
+/// Histogram buckets of equal width over [0, 1]; negative scores count towards the first
+pub const HISTOGRAM_BUCKETS: usize = 20;
+
+/// Overall score distribution, reported at the end to aid threshold selection
+pub struct Distribution {
+    pub count: usize,
+    pub mean: f32,
+    pub stddev: f32,
+    pub min: f32,
+    pub max: f32,
+    pub p50: f32,
+    pub p90: f32,
+    pub p95: f32,
+    pub p99: f32,
+    pub p999: f32,
+    pub p9999: f32,
+    pub histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+impl Distribution {
+    pub fn from_scores(scores: &[f32]) -> Self {
+        let mut all_scores: Vec<f32> = scores.to_vec();
+        all_scores.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+        let q = |p: f32| -> f32 {
+            // Return the p-quantile (0..=1) using nearest-rank on sorted ascending
+            if all_scores.is_empty() {
+                return 0.0;
+            }
+            let n = all_scores.len();
+            let idx = ((n as f32 - 1.0) * p).round() as usize;
+            all_scores[idx]
+        };
+
+        let n = all_scores.len().max(1) as f64;
+        let mean = all_scores.iter().map(|&s| s as f64).sum::<f64>() / n;
+        let var = all_scores
+            .iter()
+            .map(|&s| (s as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let mut histogram = [0usize; HISTOGRAM_BUCKETS];
+        for &s in &all_scores {
+            let bucket = (s.max(0.0) * HISTOGRAM_BUCKETS as f32) as usize;
+            histogram[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        }
+
+        Self {
+            count: all_scores.len(),
+            mean: mean as f32,
+            stddev: var.sqrt() as f32,
+            min: *all_scores.first().unwrap_or(&0.0),
+            max: *all_scores.last().unwrap_or(&0.0),
+            p50: q(0.50),
+            p90: q(0.90),
+            p95: q(0.95),
+            p99: q(0.99),
+            p999: q(0.999),
+            p9999: q(0.9999),
+            histogram,
+        }
+    }
+
+    /// Fields shared by the JSON summary object and `--stats-format json`
+    pub fn to_json(&self) -> Value {
+        json!({
+            "scored_lines": self.count,
+            "mean": round_score(self.mean),
+            "stddev": round_score(self.stddev),
+            "distribution": {
+                "min": round_score(self.min),
+                "p50": round_score(self.p50),
+                "p90": round_score(self.p90),
+                "p95": round_score(self.p95),
+                "p99": round_score(self.p99),
+                "p99.9": round_score(self.p999),
+                "p99.99": round_score(self.p9999),
+                "max": round_score(self.max),
+            },
+            "suggested_thresholds": {
+                "top_5%": round_score(self.p95),
+                "top_1%": round_score(self.p99),
+                "top_0.1%": round_score(self.p999),
+                "top_0.01%": round_score(self.p9999),
+            },
+            "histogram": {
+                "bucket_width": 1.0 / HISTOGRAM_BUCKETS as f64,
+                "counts": self.histogram,
+            },
+        })
+    }
+
+    /// `metric,value` rows; histogram buckets are named by their lower and upper bound
+    pub fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        let rows = [
+            ("scored_lines", self.count as f32),
+            ("mean", self.mean),
+            ("stddev", self.stddev),
+            ("min", self.min),
+            ("p50", self.p50),
+            ("p90", self.p90),
+            ("p95", self.p95),
+            ("p99", self.p99),
+            ("p99.9", self.p999),
+            ("p99.99", self.p9999),
+            ("max", self.max),
+        ];
+        for (name, value) in rows {
+            writeln!(out, "{},{}", name, round_score(value))?;
+        }
+        let width = 1.0 / HISTOGRAM_BUCKETS as f32;
+        for (i, count) in self.histogram.iter().enumerate() {
+            let lo = i as f32 * width;
+            writeln!(out, "hist_{:.2}_{:.2},{}", lo, lo + width, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Running score distribution for --stream: exact count, mean, extremes and histogram,
+/// and P² estimates of the reported percentiles, all in constant space
+pub struct Sketch {
+    count: usize,
+    mean: f64,
+    /// Sum of squared deviations from the mean (Welford)
+    m2: f64,
+    min: f32,
+    max: f32,
+    /// p50, p90, p95, p99, p99.9, p99.99
+    quantiles: [Quantile; 6],
+    histogram: [usize; HISTOGRAM_BUCKETS],
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            quantiles: [0.5, 0.9, 0.95, 0.99, 0.999, 0.9999].map(Quantile::new),
+            histogram: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+}
+
+impl Sketch {
+    pub fn add(&mut self, score: f32) {
+        self.count += 1;
+        let delta = score as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (score as f64 - self.mean);
+        self.min = self.min.min(score);
+        self.max = self.max.max(score);
+        for quantile in &mut self.quantiles {
+            quantile.add(score as f64);
+        }
+        let bucket = (score.max(0.0) * HISTOGRAM_BUCKETS as f32) as usize;
+        self.histogram[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+
+    /// The distribution so far, reported like batch mode's
+    pub fn distribution(&self) -> Distribution {
+        let q = |i: usize| self.quantiles[i].estimate() as f32;
+        let seen = self.count > 0;
+        Distribution {
+            count: self.count,
+            mean: self.mean as f32,
+            stddev: (self.m2 / self.count.max(1) as f64).sqrt() as f32,
+            min: if seen { self.min } else { 0.0 },
+            max: if seen { self.max } else { 0.0 },
+            p50: q(0),
+            p90: q(1),
+            p95: q(2),
+            p99: q(3),
+            p999: q(4),
+            p9999: q(5),
+            histogram: self.histogram,
+        }
+    }
+}
+
+/// Streaming estimate of one quantile with the P² algorithm (Jain & Chlamtac, 1985):
+/// five markers track the minimum, the p/2, p and (1+p)/2 quantiles and the maximum,
+/// and are nudged towards their ideal positions with a parabolic fit as values arrive
+pub struct Quantile {
+    p: f64,
+    count: usize,
+    /// Marker heights, the first five values until there are five
+    heights: [f64; 5],
+    /// Actual and desired marker positions (1-based ranks), and how the latter grow
+    positions: [f64; 5],
+    desired: [f64; 5],
+    increments: [f64; 5],
+}
+
+impl Quantile {
+    /// `p` in (0, 1)
+    pub fn new(p: f64) -> Self {
+        Self {
+            p,
+            count: 0,
+            heights: [0.0; 5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    pub fn p(&self) -> f64 {
+        self.p
+    }
+
+    /// Values seen so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn add(&mut self, x: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = x;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights
+                    .sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            }
+            return;
+        }
+        self.count += 1;
+
+        // Cell the value falls in, stretching the extremes if needed
+        let h = &mut self.heights;
+        let k = if x < h[0] {
+            h[0] = x;
+            0
+        } else if x >= h[4] {
+            h[4] = x;
+            3
+        } else {
+            (1..5).find(|&i| x < h[i]).map_or(3, |i| i - 1)
+        };
+        for position in &mut self.positions[k + 1..] {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(self.increments) {
+            *desired += increment;
+        }
+
+        // Move the middle markers by one rank where they lag or lead
+        for i in 1..4 {
+            let n = &self.positions;
+            let d = self.desired[i] - n[i];
+            if (d >= 1.0 && n[i + 1] - n[i] > 1.0) || (d <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = d.signum();
+                let h = &self.heights;
+                let parabolic = h[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (h[i + 1] - h[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (h[i] - h[i - 1]) / (n[i] - n[i - 1]));
+                let height = if h[i - 1] < parabolic && parabolic < h[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    h[i] + d * (h[j] - h[i]) / (n[j] - n[i])
+                };
+                self.heights[i] = height;
+                self.positions[i] += d;
+            }
+        }
+    }
+
+    /// Current estimate; exact (nearest rank) while fewer than five values were seen
+    pub fn estimate(&self) -> f64 {
+        if self.count >= 5 {
+            return self.heights[2];
+        }
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mut seen = self.heights[..self.count].to_vec();
+        seen.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        seen[((self.count - 1) as f64 * self.p).round() as usize]
+    }
+}
+
+/// Parse a percentile like `p99.9` or `99.9` into a quantile (0.999)
+pub fn parse_percentile(s: &str) -> Result<f64, String> {
+    let value = s.trim().trim_start_matches(['p', 'P']);
+    match value.parse::<f64>() {
+        Ok(pct) if pct > 0.0 && pct < 100.0 => Ok(pct / 100.0),
+        _ => Err(format!(
+            "invalid percentile '{}' (expected e.g. p99 or p99.9, between 0 and 100)",
+            s
+        )),
+    }
+}
+
+/// Scores as JSON numbers without f32 → f64 noise (0.734 rather than 0.7339999675750732)
+pub fn round_score(score: f32) -> f64 {
+    (score as f64 * 10_000.0).round() / 10_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantile(p: f64, values: &[f64]) -> Quantile {
+        let mut quantile = Quantile::new(p);
+        for &x in values {
+            quantile.add(x);
+        }
+        quantile
+    }
+
+    #[test]
+    fn exact_before_five_values() {
+        assert_eq!(quantile(0.5, &[]).estimate(), 0.0);
+        assert_eq!(quantile(0.5, &[3.0]).estimate(), 3.0);
+        assert_eq!(quantile(0.5, &[3.0, 1.0, 2.0]).estimate(), 2.0);
+        assert_eq!(quantile(0.9, &[4.0, 1.0, 3.0, 2.0]).estimate(), 4.0);
+        assert_eq!(quantile(0.1, &[4.0, 1.0, 3.0, 2.0]).estimate(), 1.0);
+    }
+
+    #[test]
+    fn p2_matches_the_paper() {
+        // The worked example of Jain & Chlamtac's paper, whose median estimate ends at 4.44
+        let values = [
+            0.02, 0.15, 0.74, 3.39, 0.83, 22.37, 10.15, 15.43, 38.62, 15.92, 34.60, 10.28, 1.47,
+            0.40, 0.05, 11.39, 0.27, 0.42, 0.09, 11.37,
+        ];
+        let median = quantile(0.5, &values);
+        assert_eq!(median.count(), 20);
+        assert!(
+            (median.estimate() - 4.44).abs() < 0.005,
+            "{}",
+            median.estimate()
+        );
+        assert_eq!(median.heights[0], 0.02);
+        assert_eq!(median.heights[4], 38.62);
+    }
+
+    #[test]
+    fn p2_converges_on_a_uniform_stream() {
+        // Every value in [0, 1) in steps of 1/10000, in a scrambled order
+        let values: Vec<f64> = (0..10_000)
+            .map(|i| (i * 7919 % 10_000) as f64 / 10_000.0)
+            .collect();
+        for p in [0.5, 0.9, 0.99, 0.999] {
+            let estimate = quantile(p, &values).estimate();
+            assert!((estimate - p).abs() < 0.01, "p{}: {}", p, estimate);
+        }
+    }
+
+    #[test]
+    fn sketch_tracks_moments_and_histogram() {
+        let mut sketch = Sketch::default();
+        let empty = sketch.distribution();
+        assert_eq!(
+            (empty.count, empty.min, empty.max, empty.p50),
+            (0, 0.0, 0.0, 0.0)
+        );
+
+        for score in [0.2, 0.4, 0.4, 0.6, -0.1, 1.0] {
+            sketch.add(score);
+        }
+        let dist = sketch.distribution();
+        assert_eq!(dist.count, 6);
+        assert_eq!((dist.min, dist.max), (-0.1, 1.0));
+        assert!((dist.mean - 0.4166667).abs() < 1e-6);
+        assert!((dist.stddev - 0.3387).abs() < 1e-4);
+        assert_eq!(dist.histogram[0], 1);
+        assert_eq!(dist.histogram[4], 1);
+        assert_eq!(dist.histogram[8], 2);
+        assert_eq!(dist.histogram[12], 1);
+        assert_eq!(dist.histogram[HISTOGRAM_BUCKETS - 1], 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        assert_eq!(parse_percentile("p50"), Ok(0.5));
+        assert_eq!(parse_percentile(" P99 "), Ok(0.99));
+        assert!((parse_percentile("99.9").unwrap() - 0.999).abs() < 1e-12);
+        for bad in ["p0", "100", "p", "p-1", "high"] {
+            assert!(parse_percentile(bad).is_err(), "{}", bad);
+        }
+    }
+}
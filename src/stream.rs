@@ -0,0 +1,1090 @@
+use crate::chunk::{self, Chunker};
+use crate::cli::{Cli, MatchArgs};
+use crate::explain::{self, Contribution};
+use crate::fields::Extractor;
+use crate::filter::LineFilter;
+use crate::follow;
+use crate::lexical::Bm25;
+use crate::output::{self, LineOut, WindowOut};
+use crate::preprocess::Preprocessor;
+use crate::query::{self, Queries};
+use crate::queue::{Pop, Queue};
+use crate::record::{Assembler, Framing, RecordReader, Unit};
+use crate::stats::{Quantile, Sketch};
+use crate::table::Columns;
+use crate::{cosine_similarity, encode_normalized};
+use anyhow::{ensure, Result};
+use model2vec_rs::model::StaticModel;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Stdout, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Parse a duration like `200ms`, `2s`, `1.5s` or `5m`; a bare number is milliseconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        other => {
+            return Err(format!(
+                "unknown duration unit '{}' (use ms, s, m or h)",
+                other
+            ))
+        }
+    };
+    Duration::try_from_secs_f64(num * scale).map_err(|_| format!("duration '{}' is too long", s))
+}
+
+/// Process endless input as a pipeline: a reader thread fills a bounded queue, the
+/// main thread cuts it into micro-batches (flushed once --batch-size lines arrived or
+/// --flush-interval passed since the first one) and prepares them, rayon workers encode
+/// and score several batches at once, and a printer thread puts them back in input order.
+/// When the queue is full, --overflow decides between holding the reader back and
+/// dropping lines.
+pub fn run(cli: &Cli, model: &StaticModel, queries: &Queries, paths: &[PathBuf]) -> Result<()> {
+    let framing = Arc::new(Framing::new(&cli.records)?);
+    let batch_size = cli.model_args.batch_size.max(1);
+    let mut scorer = Scorer {
+        cli,
+        queries,
+        filter: LineFilter::new(&cli.filter)?,
+        chunker: Chunker::new(&cli.chunk)?,
+        preprocessor: Preprocessor::new(&cli.preprocess)?,
+        extractor: Extractor::new(&cli.fields),
+        columns: None,
+        table: match *framing {
+            Framing::Table(delimiter) => Some(delimiter),
+            _ => None,
+        },
+        bm25: Bm25::default(),
+        embedded: 0,
+    };
+    let encoder = Encoder {
+        cli,
+        model,
+        queries,
+    };
+
+    // Reading blocks, so it gets its own thread (one per file with --follow). Records
+    // that are held back until the next one starts are put together from lines here
+    // instead, so one can be let go once the input goes quiet.
+    let queue = Arc::new(Queue::new(cli.queue_size, cli.overflow));
+    let read_as = if framing.holds_back() {
+        Arc::new(Framing::Lines)
+    } else {
+        Arc::clone(&framing)
+    };
+    if cli.follow {
+        for path in paths {
+            ensure!(
+                path.as_os_str() != "-" && !path.is_dir(),
+                "--follow takes files, not {}",
+                path.display()
+            );
+            let input = Arc::clone(&queue);
+            let framing = Arc::clone(&read_as);
+            let path = path.clone();
+            let label: Arc<str> = path.display().to_string().into();
+            // Followed files never end, so the queue is never closed
+            thread::spawn(move || {
+                let followed = follow::follow(&path, &framing, |unit, reset| {
+                    input.push(Ok(Incoming {
+                        path: Some(Arc::clone(&label)),
+                        unit,
+                        reset,
+                    }))
+                });
+                if let Err(err) = followed {
+                    input.push(Err(err));
+                }
+            });
+        }
+    } else {
+        let input = Arc::clone(&queue);
+        let framing = Arc::clone(&read_as);
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let mut reader = RecordReader::new(stdin.lock(), &framing);
+            loop {
+                match reader.next_record() {
+                    Ok(Some(unit)) => input.push(Ok(Incoming {
+                        path: None,
+                        unit,
+                        reset: false,
+                    })),
+                    Ok(None) => break,
+                    Err(err) => {
+                        input.push(Err(err));
+                        break;
+                    }
+                }
+            }
+            input.close();
+        });
+    }
+
+    // One slot per batch being scored or waiting to print: with every slot taken the
+    // main thread stops taking lines, the queue fills up and --overflow kicks in
+    let (slot_tx, slot_rx) = mpsc::sync_channel::<()>(rayon::current_num_threads().max(1));
+    let (done_tx, done_rx) = mpsc::channel::<(usize, Vec<Scored>)>();
+    let args = &cli.matching;
+    let encoder = &encoder;
+    let result = thread::scope(|s| {
+        s.spawn(move || {
+            let mut sink = match args.top {
+                Some(top) => Sink::Top(TopWindows::new(cli, encoder, top)),
+                None => Sink::Context(Printer::new(io::stdout(), args, queries.multi_labels())),
+            };
+            let mut stats = Stats::new(cli, encoder);
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            loop {
+                let deadline = match (sink.deadline(), stats.deadline()) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                let done = match deadline {
+                    None => done_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    Some(deadline) => {
+                        done_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                };
+                match done {
+                    Ok((seq, scored)) => {
+                        pending.insert(seq, scored);
+                        while let Some(mut scored) = pending.remove(&next) {
+                            stats.add(&mut scored);
+                            for scored in scored {
+                                sink.push(scored);
+                            }
+                            next += 1;
+                            let _ = slot_rx.recv();
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => sink.tick(),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                stats.tick(sink.matches());
+            }
+            sink.finish();
+            stats.report(sink.matches());
+        });
+
+        let result = rayon::in_place_scope(|pool| -> Result<()> {
+            let mut batch: Vec<Incoming> = Vec::with_capacity(batch_size);
+            let mut records = framing.holds_back().then(|| Records::new(&framing));
+            let mut deadline: Option<Instant> = None;
+            // When records held back are let go if nothing else arrives
+            let mut quiet: Option<Instant> = None;
+            let mut seq = 0;
+            loop {
+                let wake = match (deadline, quiet) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                let ended = match queue.pop(wake) {
+                    Pop::Item(incoming) => {
+                        let incoming = incoming?;
+                        match &mut records {
+                            Some(records) => {
+                                records.push(incoming, &mut batch);
+                                quiet = records
+                                    .holding()
+                                    .then(|| Instant::now() + cli.flush_interval);
+                            }
+                            None => batch.push(incoming),
+                        }
+                        if !batch.is_empty() {
+                            deadline.get_or_insert_with(|| Instant::now() + cli.flush_interval);
+                        }
+                        if batch.len() < batch_size {
+                            continue;
+                        }
+                        false
+                    }
+                    Pop::Timeout => {
+                        if quiet.is_some_and(|quiet| quiet <= Instant::now()) {
+                            if let Some(records) = &mut records {
+                                records.flush(&mut batch);
+                            }
+                            quiet = None;
+                        }
+                        false
+                    }
+                    Pop::Closed => {
+                        if let Some(records) = &mut records {
+                            records.flush(&mut batch);
+                        }
+                        true
+                    }
+                };
+                deadline = None;
+                if !batch.is_empty() {
+                    let prepared = scorer.prepare(std::mem::take(&mut batch))?;
+                    let _ = slot_tx.send(());
+                    let done = done_tx.clone();
+                    pool.spawn(move |_| {
+                        let _ = done.send((seq, encoder.score(prepared)));
+                    });
+                    seq += 1;
+                }
+                if ended {
+                    return Ok(());
+                }
+            }
+        });
+        // Let the printer finish once the last batch is in
+        drop(done_tx);
+        result
+    });
+
+    if let Some(extractor) = &scorer.extractor {
+        extractor.report(scorer.embedded);
+    }
+    let dropped = queue.dropped();
+    if dropped > 0 {
+        eprintln!(
+            "vecgrep: dropped {} lines that arrived while the queue was full (--queue-size {})",
+            dropped, cli.queue_size
+        );
+    }
+    result
+}
+
+/// --record-start records and paragraphs of each input of a stream, put together from
+/// its lines; what a quiet input holds back is let go with `flush`
+struct Records<'a> {
+    framing: &'a Framing,
+    /// Per input, the record held back and whether it starts a reopened file
+    inputs: BTreeMap<Option<Arc<str>>, (Assembler<'a>, bool)>,
+}
+
+impl<'a> Records<'a> {
+    fn new(framing: &'a Framing) -> Self {
+        Self {
+            framing,
+            inputs: BTreeMap::new(),
+        }
+    }
+
+    /// Add a line, moving the record it completed (if any) to `out`
+    fn push(&mut self, line: Incoming, out: &mut Vec<Incoming>) {
+        let Incoming { path, unit, reset } = line;
+        let framing = self.framing;
+        let (assembler, held_reset) = self
+            .inputs
+            .entry(path.clone())
+            .or_insert_with(|| (Assembler::new(framing), false));
+        if reset {
+            // The rest of the old file ends its last record
+            if let Some(unit) = assembler.flush() {
+                out.push(Incoming {
+                    path: path.clone(),
+                    unit,
+                    reset: *held_reset,
+                });
+            }
+            *held_reset = true;
+        }
+        if let Some(unit) = assembler.push(unit) {
+            out.push(Incoming {
+                path,
+                unit,
+                reset: std::mem::take(held_reset),
+            });
+        }
+    }
+
+    fn holding(&self) -> bool {
+        self.inputs
+            .values()
+            .any(|(assembler, _)| assembler.holding())
+    }
+
+    /// Move every record held back to `out`
+    fn flush(&mut self, out: &mut Vec<Incoming>) {
+        for (path, (assembler, reset)) in &mut self.inputs {
+            if let Some(unit) = assembler.flush() {
+                out.push(Incoming {
+                    path: path.clone(),
+                    unit,
+                    reset: std::mem::take(reset),
+                });
+            }
+        }
+    }
+}
+
+/// A line or record as read, with the file it came from under --follow
+struct Incoming {
+    path: Option<Arc<str>>,
+    unit: Unit,
+    /// First record since the file was (re)opened after truncation or rotation
+    reset: bool,
+}
+
+/// A line or record with its scores, ready to print
+struct Scored {
+    path: Option<Arc<str>>,
+    unit: Unit,
+    /// Earlier lines of the same input are no context for this one
+    reset: bool,
+    /// Score per query
+    row: Vec<f32>,
+    score: f32,
+    is_match: bool,
+    /// Left out by --prefilter/--exclude, so not scored
+    filtered: bool,
+    /// Best window with --chunk-size, when the line was split
+    window: Option<Range<usize>>,
+    explain: Vec<Contribution>,
+}
+
+/// Where a unit's windows are among the texts of a batch, and their spans in the unit
+struct Plan {
+    texts: Range<usize>,
+    spans: Vec<Range<usize>>,
+}
+
+/// A batch with everything that depends on earlier batches worked out, so it can be
+/// encoded and scored independently of the others
+struct Prepared {
+    units: Vec<Incoming>,
+    /// `None` for units left out by the filter
+    plans: Vec<Option<Plan>>,
+    /// Texts to embed
+    texts: Vec<String>,
+    /// BM25 scores per text with --lexical-weight (`Queries::is_hybrid`), else empty
+    lexical: Vec<Vec<f32>>,
+}
+
+/// State carried from batch to batch, updated in input order
+struct Scorer<'a> {
+    cli: &'a Cli,
+    queries: &'a Queries,
+    filter: LineFilter,
+    chunker: Option<Chunker>,
+    preprocessor: Option<Preprocessor>,
+    extractor: Option<Extractor>,
+    /// Set from the header row with --csv/--tsv
+    columns: Option<Columns>,
+    table: Option<char>,
+    /// BM25 statistics grow with the stream
+    bm25: Bm25,
+    /// Lines that passed the filter, for the --json-field report
+    embedded: usize,
+}
+
+impl Scorer<'_> {
+    /// Filter, extract, chunk and clean a batch's lines into the texts to embed
+    fn prepare(&mut self, units: Vec<Incoming>) -> Result<Prepared> {
+        let mut units = units.into_iter();
+        if let (Some(delimiter), None) = (self.table, &self.columns) {
+            // With --csv/--tsv the first row is the header: printed, not scored
+            if let Some(Incoming { unit: header, .. }) = units.next() {
+                let csv_column = self.cli.records.csv_column.as_deref();
+                self.columns = Some(Columns::new(delimiter, csv_column, &header.text)?);
+                output::print_header(&self.cli.matching, &header.text);
+            }
+        }
+
+        // Texts to embed, and for each unit which of them are its windows
+        let mut texts: Vec<String> = Vec::new();
+        let mut plans: Vec<Option<Plan>> = Vec::new();
+        let units: Vec<Incoming> = units.collect();
+        for Incoming { unit, .. } in &units {
+            if !self.filter.keep(&unit.text) {
+                plans.push(None);
+                continue;
+            }
+            self.embedded += 1;
+            let text = match (&self.extractor, &self.columns) {
+                (Some(extractor), _) => extractor.embed_text(&unit.text),
+                (_, Some(columns)) => Cow::Owned(columns.embed_text(&unit.text)),
+                _ => Cow::Borrowed(unit.text.as_str()),
+            };
+            let spans = match &self.chunker {
+                Some(chunker) => chunker.windows(&text),
+                None => std::iter::once(0..text.len()).collect(),
+            };
+            let start = texts.len();
+            for span in &spans {
+                let window = &text[span.clone()];
+                texts.push(match &self.preprocessor {
+                    Some(preprocessor) => preprocessor.apply(window).into_owned(),
+                    None => window.to_string(),
+                });
+            }
+            plans.push(Some(Plan {
+                texts: start..texts.len(),
+                spans,
+            }));
+        }
+
+        let queries = self.queries;
+        let lexical: Vec<Vec<f32>> = if queries.is_hybrid() {
+            texts
+                .iter()
+                .map(|text| {
+                    self.bm25.add(text);
+                    self.bm25.score(&queries.terms, text)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Prepared {
+            units,
+            plans,
+            texts,
+            lexical,
+        })
+    }
+}
+
+/// Encodes and scores prepared batches; shared by the workers
+struct Encoder<'a> {
+    cli: &'a Cli,
+    model: &'a StaticModel,
+    queries: &'a Queries,
+}
+
+impl Encoder<'_> {
+    /// Score a batch with one encoder call for all of its lines (or their windows)
+    fn score(&self, batch: Prepared) -> Vec<Scored> {
+        let Prepared {
+            units,
+            plans,
+            texts,
+            lexical,
+        } = batch;
+        let batch_size = self.cli.model_args.batch_size;
+        let embeddings = if texts.is_empty() {
+            Vec::new()
+        } else {
+            encode_normalized(self.model, &texts, batch_size)
+        };
+
+        let queries = self.queries;
+        let args = &self.cli.matching;
+        // With --top every line is a candidate, picked when its window closes, and
+        // with --auto-threshold one decided against the scores before it
+        let candidate = args.top.is_some() || self.cli.auto_threshold.is_some();
+        let mut scored: Vec<Scored> = units
+            .into_iter()
+            .zip(plans)
+            .map(|(Incoming { path, unit, reset }, plan)| {
+                let filtered = plan.is_none();
+                let (row, dropped, window) = match plan {
+                    None => (vec![0.0; queries.vecs.len()], true, None),
+                    Some(Plan {
+                        texts: range,
+                        spans,
+                    }) => {
+                        let mut rows: Vec<(Vec<f32>, bool)> = range
+                            .map(|w| {
+                                let lexical = lexical.get(w).map_or(&[][..], Vec::as_slice);
+                                queries
+                                    .score_line(|q| cosine_similarity(q, &embeddings[w]), lexical)
+                            })
+                            .collect();
+                        let best =
+                            chunk::pick(rows.iter().map(|(row, d)| (query::best(row).1, *d)));
+                        let k = best.unwrap_or(0);
+                        let window = (spans.len() > 1).then(|| spans[k].clone());
+                        let (row, dropped) = rows.swap_remove(k);
+                        (row, dropped, window)
+                    }
+                };
+                let score = query::best(&row).1;
+                let is_match = (candidate || score >= args.threshold) && !dropped;
+                Scored {
+                    path,
+                    unit,
+                    reset,
+                    row,
+                    score,
+                    is_match,
+                    filtered,
+                    window,
+                    explain: Vec::new(),
+                }
+            })
+            .collect();
+
+        // --explain the batch's matches with one encoder call for all of their words
+        if args.explain && !candidate {
+            let matched: Vec<&mut Scored> = scored.iter_mut().filter(|s| s.is_match).collect();
+            if !matched.is_empty() {
+                let lines: Vec<&str> = matched.iter().map(|s| s.unit.text.as_str()).collect();
+                let rows: Vec<&[f32]> = matched.iter().map(|s| s.row.as_slice()).collect();
+                let explanations = self.explain(&lines, &rows);
+                for (scored, explain) in matched.into_iter().zip(explanations) {
+                    scored.explain = explain;
+                }
+            }
+        }
+        scored
+    }
+
+    /// --explain contributions for lines, each against its best query
+    fn explain(&self, lines: &[&str], rows: &[&[f32]]) -> Vec<Vec<Contribution>> {
+        let vecs: Vec<&[f32]> = rows.iter().map(|row| self.queries.best_vec(row)).collect();
+        explain::explain(lines, &vecs, |words| {
+            encode_normalized(self.model, words, self.cli.model_args.batch_size)
+        })
+    }
+}
+
+/// Set by SIGUSR1 to ask for a --stream statistics report
+static REPORT: AtomicBool = AtomicBool::new(false);
+
+/// How often the printer checks for SIGUSR1 while input is quiet
+const SIGNAL_POLL: Duration = Duration::from_millis(250);
+
+#[cfg(unix)]
+fn report_on_sigusr1() {
+    extern "C" fn handle(_: libc::c_int) {
+        REPORT.store(true, AtomicOrdering::Relaxed);
+    }
+    let handle: extern "C" fn(libc::c_int) = handle;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGUSR1, handle as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn report_on_sigusr1() {}
+
+/// Running score distribution of the stream, and --auto-threshold matching against it;
+/// lives on the printer thread so lines are seen in input order
+struct Stats<'a> {
+    args: &'a MatchArgs,
+    encoder: &'a Encoder<'a>,
+    sketch: Sketch,
+    /// The --auto-threshold percentile, and the lines it needs before it matches anything
+    auto: Option<Quantile>,
+    warmup: usize,
+    /// Lines left out by --prefilter/--exclude
+    skipped: usize,
+    interval: Option<Duration>,
+    next_report: Option<Instant>,
+}
+
+impl<'a> Stats<'a> {
+    fn new(cli: &'a Cli, encoder: &'a Encoder<'a>) -> Self {
+        report_on_sigusr1();
+        Self {
+            args: &cli.matching,
+            encoder,
+            sketch: Sketch::default(),
+            auto: cli.auto_threshold.map(Quantile::new),
+            // Enough lines that one in 1/(1-p) lies above the percentile
+            warmup: cli
+                .auto_threshold
+                .map_or(0, |p| ((1.0 / (1.0 - p)).ceil() as usize).max(5)),
+            skipped: 0,
+            interval: cli.stats_interval,
+            next_report: cli.stats_interval.map(|interval| Instant::now() + interval),
+        }
+    }
+
+    /// Record a batch's scores; with --auto-threshold also decide (and explain) its matches
+    fn add(&mut self, batch: &mut [Scored]) {
+        for scored in batch.iter_mut() {
+            if scored.filtered {
+                self.skipped += 1;
+                continue;
+            }
+            if let Some(auto) = &mut self.auto {
+                scored.is_match = scored.is_match
+                    && auto.count() >= self.warmup
+                    && scored.score as f64 >= auto.estimate();
+                auto.add(scored.score as f64);
+            }
+            self.sketch.add(scored.score);
+        }
+
+        if self.auto.is_some() && self.args.explain {
+            let mut matches: Vec<&mut Scored> = batch.iter_mut().filter(|s| s.is_match).collect();
+            let lines: Vec<&str> = matches.iter().map(|s| s.unit.text.as_str()).collect();
+            let rows: Vec<&[f32]> = matches.iter().map(|s| s.row.as_slice()).collect();
+            let explained = self.encoder.explain(&lines, &rows);
+            for (scored, explain) in matches.iter_mut().zip(explained) {
+                scored.explain = explain;
+            }
+        }
+    }
+
+    /// When to wake up for the next report or to check for SIGUSR1
+    fn deadline(&self) -> Option<Instant> {
+        let poll = Instant::now() + SIGNAL_POLL;
+        Some(self.next_report.map_or(poll, |next| next.min(poll)))
+    }
+
+    /// Report if --stats-interval is up or SIGUSR1 arrived
+    fn tick(&mut self, matches: usize) {
+        let now = Instant::now();
+        let due = self.next_report.is_some_and(|next| now >= next);
+        if REPORT.swap(false, AtomicOrdering::Relaxed) || due {
+            self.report(matches);
+        }
+        if let (true, Some(interval)) = (due, self.interval) {
+            self.next_report = Some(now + interval);
+        }
+    }
+
+    fn report(&self, matches: usize) {
+        let selection_summary = match (&self.auto, self.args.top) {
+            (Some(auto), _) if auto.count() < self.warmup => format!(
+                "matches: {} (--auto-threshold {} waits for {} lines, {} so far)",
+                matches,
+                self.percentile(),
+                self.warmup,
+                auto.count()
+            ),
+            (Some(auto), _) => format!(
+                "matches: {} (running {} threshold {:.3})",
+                matches,
+                self.percentile(),
+                auto.estimate()
+            ),
+            (None, Some(top)) => format!("matches: {} (top {} per window)", matches, top),
+            (None, None) => format!(
+                "matches: {} (threshold {:.2})",
+                matches, self.args.threshold
+            ),
+        };
+        let _ = output::report_stats(
+            self.args,
+            &self.sketch.distribution(),
+            self.skipped,
+            matches,
+            &selection_summary,
+        );
+    }
+
+    /// The --auto-threshold percentile as given, e.g. p99.9
+    fn percentile(&self) -> String {
+        let p = self.auto.as_ref().map_or(0.0, |auto| auto.p());
+        format!("p{}", (p * 1e6).round() / 1e4)
+    }
+}
+
+/// Where the printer thread sends scored lines
+enum Sink<'a> {
+    /// Matches with -A/-B context as they come
+    Context(Printer<'a>),
+    /// The --top best of each window
+    Top(TopWindows<'a>),
+}
+
+impl Sink<'_> {
+    fn push(&mut self, scored: Scored) {
+        match self {
+            Sink::Context(printer) => printer.push(scored),
+            Sink::Top(windows) => windows.push(scored),
+        }
+    }
+
+    /// When the current window ends even if no more lines arrive
+    fn deadline(&self) -> Option<Instant> {
+        match self {
+            Sink::Context(_) => None,
+            Sink::Top(windows) => windows.deadline(),
+        }
+    }
+
+    fn tick(&mut self) {
+        if let Sink::Top(windows) = self {
+            windows.roll(Instant::now());
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Sink::Top(windows) = self {
+            windows.emit();
+        }
+    }
+
+    /// Matches printed so far
+    fn matches(&self) -> usize {
+        match self {
+            Sink::Context(printer) => printer.matches,
+            Sink::Top(windows) => windows.printer.matches,
+        }
+    }
+}
+
+/// Tumbling windows of --window-lines lines or --window-secs seconds (by when lines are
+/// scored); when one ends its --top best lines are printed in input order
+struct TopWindows<'a> {
+    printer: Printer<'a>,
+    encoder: &'a Encoder<'a>,
+    top: usize,
+    lines: Option<usize>,
+    secs: Option<u64>,
+    started: Instant,
+    /// Current window, 0-based
+    index: usize,
+    /// Candidates with their position in the window, trimmed to the best `top` now and then
+    best: Vec<(usize, Scored)>,
+    /// Lines read in the current window
+    read: usize,
+    first_line: usize,
+    last_line: usize,
+}
+
+impl<'a> TopWindows<'a> {
+    fn new(cli: &'a Cli, encoder: &'a Encoder<'a>, top: usize) -> Self {
+        Self {
+            printer: Printer::new(io::stdout(), &cli.matching, encoder.queries.multi_labels()),
+            encoder,
+            top,
+            lines: cli.window_lines.map(|n| n as usize),
+            secs: cli.window_secs,
+            started: Instant::now(),
+            index: 0,
+            best: Vec::new(),
+            read: 0,
+            first_line: 0,
+            last_line: 0,
+        }
+    }
+
+    fn push(&mut self, scored: Scored) {
+        self.roll(Instant::now());
+        if self.read == 0 {
+            self.first_line = scored.unit.line_number;
+        }
+        self.last_line = scored.unit.line_number + scored.unit.text.matches('\n').count();
+        self.read += 1;
+        if scored.is_match {
+            self.best.push((self.read, scored));
+            if self.best.len() >= 2 * self.top.max(1) {
+                self.trim();
+            }
+        }
+        if self.lines.is_some_and(|n| self.read >= n) {
+            self.emit();
+            self.index += 1;
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.secs
+            .map(|secs| self.started + Duration::from_secs(secs * (self.index as u64 + 1)))
+    }
+
+    /// Close the current --window-secs window once `now` is past it
+    fn roll(&mut self, now: Instant) {
+        if let Some(secs) = self.secs {
+            let index = (now.duration_since(self.started).as_secs() / secs) as usize;
+            if index > self.index {
+                self.emit();
+                self.index = index;
+            }
+        }
+    }
+
+    fn trim(&mut self) {
+        self.best
+            .sort_by(|(_, a), (_, b)| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        self.best.truncate(self.top);
+    }
+
+    /// Print the current window's marker and best lines, then start it over;
+    /// windows without lines print nothing
+    fn emit(&mut self) {
+        if self.read == 0 {
+            return;
+        }
+        self.trim();
+        self.best.sort_by_key(|(position, _)| *position);
+        if self.printer.args.explain {
+            let lines: Vec<&str> = self
+                .best
+                .iter()
+                .map(|(_, s)| s.unit.text.as_str())
+                .collect();
+            let rows: Vec<&[f32]> = self.best.iter().map(|(_, s)| s.row.as_slice()).collect();
+            let explained = self.encoder.explain(&lines, &rows);
+            for ((_, scored), explain) in self.best.iter_mut().zip(explained) {
+                scored.explain = explain;
+            }
+        }
+
+        output::print_window(
+            self.printer.args,
+            &WindowOut {
+                index: self.index,
+                read: self.read,
+                selected: self.best.len(),
+                first_line: self.first_line,
+                last_line: self.last_line,
+                secs: self.secs.map(|secs| {
+                    let start = secs * self.index as u64;
+                    start..start + secs
+                }),
+            },
+        );
+        self.printer.block_id = self.index;
+        self.printer.matches += self.best.len();
+        for (_, scored) in self.best.drain(..) {
+            self.printer.print(&scored, true);
+        }
+        self.read = 0;
+    }
+}
+
+/// -A/-B state of one input; each followed file has its own
+#[derive(Default)]
+struct Context {
+    /// Recent lines for before-context
+    before_buf: VecDeque<Scored>,
+    after_remaining: usize,
+    /// Whether this input's previous line was printed
+    printed_prev_line: bool,
+}
+
+/// Prints scored lines as they come, with -A/-B context like grep
+struct Printer<'a, W: Write = Stdout> {
+    out: W,
+    args: &'a MatchArgs,
+    /// Query texts when there are several, else empty
+    labels: &'a [String],
+    contexts: HashMap<Option<Arc<str>>, Context>,
+    /// Input of the last printed line; a line from another one starts a new block
+    last_path: Option<Arc<str>>,
+    printed_any: bool,
+    block_id: usize,
+    matches: usize,
+}
+
+impl<'a, W: Write> Printer<'a, W> {
+    fn new(out: W, args: &'a MatchArgs, labels: &'a [String]) -> Self {
+        Self {
+            out,
+            args,
+            labels,
+            contexts: HashMap::new(),
+            last_path: None,
+            printed_any: false,
+            block_id: 0,
+            matches: 0,
+        }
+    }
+
+    fn push(&mut self, scored: Scored) {
+        let args = self.args;
+        let mut ctx = self.contexts.remove(&scored.path).unwrap_or_default();
+        if scored.reset {
+            // A followed file was truncated or replaced: its context is gone
+            ctx = Context::default();
+        }
+        let continues = ctx.printed_prev_line && self.last_path == scored.path;
+        if scored.is_match || ctx.after_remaining > 0 {
+            // New block separator if we didn't just print the line before it
+            if self.printed_any && !continues {
+                output::write_separator(&mut self.out, args).expect("failed printing to stdout");
+                self.block_id += 1;
+            }
+        }
+        if scored.is_match {
+            // Before-context only when starting a fresh block
+            if !ctx.printed_prev_line {
+                for before in &ctx.before_buf {
+                    self.print(before, false);
+                }
+            }
+            self.print(&scored, true);
+            self.matches += 1;
+            self.printed_any = true;
+            ctx.printed_prev_line = true;
+            ctx.after_remaining = args.after;
+            self.last_path.clone_from(&scored.path);
+        } else if ctx.after_remaining > 0 {
+            self.print(&scored, false);
+            self.printed_any = true;
+            ctx.printed_prev_line = true;
+            ctx.after_remaining -= 1;
+            self.last_path.clone_from(&scored.path);
+        } else {
+            ctx.printed_prev_line = false;
+        }
+
+        // Maintain before buffer
+        let path = scored.path.clone();
+        if args.before > 0 {
+            if ctx.before_buf.len() == args.before {
+                ctx.before_buf.pop_front();
+            }
+            ctx.before_buf.push_back(scored);
+        }
+        self.contexts.insert(path, ctx);
+    }
+
+    fn print(&mut self, scored: &Scored, is_match: bool) {
+        output::write_line(
+            &mut self.out,
+            self.args,
+            &LineOut {
+                path: scored.path.as_deref(),
+                line_number: scored.unit.line_number,
+                byte_offset: scored.unit.offset,
+                text: &scored.unit.text,
+                score: scored.score,
+                is_match,
+                block_id: self.block_id,
+                explain: if is_match { &scored.explain } else { &[] },
+                labels: self.labels,
+                query_scores: if is_match && !self.labels.is_empty() {
+                    &scored.row
+                } else {
+                    &[]
+                },
+                window: scored.window.as_ref().filter(|_| is_match),
+            },
+        )
+        .expect("failed printing to stdout");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn match_args(args: &[&str]) -> MatchArgs {
+        let argv = ["vecgrep", "--color", "never"]
+            .iter()
+            .chain(args)
+            .chain(&["query"]);
+        Cli::try_parse_from(argv).unwrap().matching
+    }
+
+    /// Lines containing "error" match
+    fn scored(unit: Unit, path: Option<&Arc<str>>, reset: bool) -> Scored {
+        let is_match = unit.text.contains("error");
+        Scored {
+            path: path.cloned(),
+            unit,
+            reset,
+            row: Vec::new(),
+            score: if is_match { 0.9 } else { 0.1 },
+            is_match,
+            filtered: false,
+            window: None,
+            explain: Vec::new(),
+        }
+    }
+
+    fn printed(printer: Printer<Vec<u8>>) -> String {
+        String::from_utf8(printer.out).unwrap()
+    }
+
+    #[test]
+    fn null_data_records_keep_their_context() {
+        // Records without newlines all start on line 1
+        let args = match_args(&["-A1", "-B1"]);
+        let mut printer = Printer::new(Vec::new(), &args, &[]);
+        let input = &b"alpha\0database error one\0beta\0gamma\0"[..];
+        let mut reader = RecordReader::new(input, &Framing::Null);
+        while let Some(unit) = reader.next_record().unwrap() {
+            printer.push(scored(unit, None, false));
+        }
+        assert_eq!(
+            printed(printer),
+            "alpha\ndatabase error one\t[0.900]\nbeta\n"
+        );
+    }
+
+    #[test]
+    fn reopened_file_starts_a_new_block() {
+        let args = match_args(&["-A1", "-B1"]);
+        let mut printer = Printer::new(Vec::new(), &args, &[]);
+        let path: Arc<str> = "app.log".into();
+        let mut reader = RecordReader::new(&b"one\nerror two\nthree\n"[..], &Framing::Lines);
+        while let Some(unit) = reader.next_record().unwrap() {
+            printer.push(scored(unit, Some(&path), false));
+        }
+        // Truncated: the lines before are gone, so no before-context and a fresh block
+        let mut reader = RecordReader::new(&b"error again\n"[..], &Framing::Lines);
+        let unit = reader.next_record().unwrap().unwrap();
+        printer.push(scored(unit, Some(&path), true));
+        assert_eq!(
+            printed(printer),
+            "app.log-1-one\napp.log:2:error two\t[0.900]\napp.log-3-three\n--\napp.log:1:error again\t[0.900]\n"
+        );
+    }
+
+    #[test]
+    fn held_records_are_let_go_per_input() {
+        let framing = Framing::Start(regex::Regex::new("^ERROR|^INFO").unwrap());
+        let mut records = Records::new(&framing);
+        let (a, b): (Arc<str>, Arc<str>) = ("a.log".into(), "b.log".into());
+        let mut out = Vec::new();
+        let mut push = |records: &mut Records, path: &Arc<str>, text: &str, reset: bool| {
+            let unit = Unit {
+                text: text.to_string(),
+                offset: 0,
+                line_number: 1,
+            };
+            let path = Some(Arc::clone(path));
+            records.push(Incoming { path, unit, reset }, &mut out);
+        };
+        push(&mut records, &a, "ERROR one", false);
+        push(&mut records, &b, "INFO two", false);
+        push(&mut records, &a, "  trace", false);
+        push(&mut records, &a, "INFO three", false);
+        // a.log was truncated: its held record ends, the next one starts afresh
+        push(&mut records, &a, "ERROR four", true);
+        assert!(records.holding());
+        records.flush(&mut out);
+        assert!(!records.holding());
+        let got: Vec<_> = out
+            .iter()
+            .map(|i| (i.path.as_deref().unwrap(), i.unit.text.as_str(), i.reset))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                ("a.log", "ERROR one\n  trace", false),
+                ("a.log", "INFO three", false),
+                ("a.log", "ERROR four", true),
+                ("b.log", "INFO two", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("200ms"), Ok(Duration::from_millis(200)));
+        assert_eq!(parse_duration("250"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration(" 5 M "), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("1e999999999999999999999h").is_err());
+        assert!(parse_duration("99999999999999999999999h").is_err());
+    }
+}
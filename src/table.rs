@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+
+/// Which field of a --csv/--tsv row gets embedded, resolved against a source's header
+pub struct Columns {
+    delimiter: char,
+    /// 0-based index of the --csv-column field, `None` to embed every field
+    index: Option<usize>,
+}
+
+impl Columns {
+    /// `column` is a header name or a 1-based index
+    pub fn new(delimiter: char, column: Option<&str>, header: &str) -> Result<Self> {
+        let index = match column {
+            None => None,
+            Some(column) => match column.parse::<usize>() {
+                Ok(0) => bail!("--csv-column indices start at 1"),
+                Ok(n) => Some(n - 1),
+                Err(_) => {
+                    let names = fields(header, delimiter);
+                    match names.iter().position(|name| name.trim() == column) {
+                        Some(i) => Some(i),
+                        None => bail!(
+                            "no column named '{}' (columns: {})",
+                            column,
+                            names.join(", ")
+                        ),
+                    }
+                }
+            },
+        };
+        Ok(Self { delimiter, index })
+    }
+
+    /// Text to embed for a row: the chosen field, or all fields joined by spaces
+    /// (also when the row is too short to have the chosen field)
+    pub fn embed_text(&self, row: &str) -> String {
+        let mut fields = fields(row, self.delimiter);
+        match self.index {
+            Some(i) if i < fields.len() => fields.swap_remove(i),
+            _ => fields.join(" "),
+        }
+    }
+}
+
+/// Fields of a row, unquoted: `"a ""b"", c"` is `a "b", c`
+pub fn fields(row: &str, delimiter: char) -> Vec<String> {
+    scan(row, delimiter).0
+}
+
+/// True when a quoted field is still open at the end of `row`, so a CSV row continues
+/// on the next line
+pub fn in_quotes(row: &str, delimiter: char) -> bool {
+    scan(row, delimiter).1
+}
+
+/// A quote only opens a field at its start; inside one, `""` is a literal quote
+fn scan(row: &str, delimiter: char) -> (Vec<String>, bool) {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.next_if_eq(&'"').is_some() {
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    (fields, quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_unquote() {
+        assert_eq!(
+            fields(r#"a,"b, c","d ""e""""#, ','),
+            ["a", "b, c", r#"d "e""#]
+        );
+        assert_eq!(fields("a,,b", ','), ["a", "", "b"]);
+        assert_eq!(fields("", ','), [""]);
+    }
+
+    #[test]
+    fn quotes_only_open_a_field_at_its_start() {
+        assert_eq!(fields("27\" monitor\t199", '\t'), ["27\" monitor", "199"]);
+        assert!(!in_quotes("27\" monitor,199", ','));
+        assert!(in_quotes(r#"1,"first line"#, ','));
+        assert!(!in_quotes(r#"1,"closed ""quote""",2"#, ','));
+    }
+}